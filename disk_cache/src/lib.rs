@@ -0,0 +1,376 @@
+//! `disk_cache`, samples kept on disk so that files are only decoded (and instruments only
+//! played through effects) once however often Plunder is restarted
+
+use std::{
+    env, fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use types::Sample;
+use xxhash_rust::xxh3::Xxh3;
+
+static DIRECTORY: LazyLock<Mutex<Option<PathBuf>>> =
+    LazyLock::new(|| Mutex::new(DiskCache::default_directory()));
+static CAPACITY: AtomicU64 = AtomicU64::new(DiskCache::CAPACITY);
+/// Counts files written, so that no two writes share a temporary file
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Samples of either channel count, as stored in the cache
+pub enum Samples {
+    Mono(Vec<Sample<1>>),
+    Stereo(Vec<Sample<2>>),
+}
+
+/// Key of samples computed from a source, which is fed to it piece by piece, see
+/// [`DiskCache::key`]
+#[derive(Default)]
+pub struct Key(Xxh3);
+
+impl Key {
+    pub fn update(&mut self, source: &[u8]) {
+        self.0.update(source);
+    }
+
+    /// The key of the source fed so far once transformed by `transform`
+    pub fn finish(mut self, transform: &[u8]) -> String {
+        self.0.update(&[0]);
+        self.0.update(transform);
+        format!("{:032x}", self.0.digest128())
+    }
+}
+
+/// A directory of samples, each stored under the hash of the source they were computed from and
+/// the transform they were computed with
+///
+/// Files of the cache hold raw frames behind a short header, so loading one is little more than
+/// reading it. Once they take up more than its capacity, those least recently used are removed.
+#[derive(Clone)]
+pub struct DiskCache {
+    directory: PathBuf,
+    /// Bytes the cache's files take up at most
+    capacity: u64,
+}
+
+const MAGIC: &[u8; 4] = b"PLDC";
+/// Version of the format of cached files, bumped whenever it (or how samples are decoded) changes
+const VERSION: u8 = 1;
+const HEADER: usize = 20;
+
+impl DiskCache {
+    /// Default capacity of the shared cache, 4GiB
+    pub const CAPACITY: u64 = 4 << 30;
+
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        DiskCache {
+            directory: directory.into(),
+            capacity: DiskCache::CAPACITY,
+        }
+    }
+
+    /// `$PLUNDER_CACHE_DIR`, or else `plunder` in the user's cache directory
+    fn default_directory() -> Option<PathBuf> {
+        if let Some(directory) = env::var_os("PLUNDER_CACHE_DIR") {
+            return Some(directory.into());
+        }
+        let cache = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+        Some(cache.join("plunder"))
+    }
+
+    /// The cache used by every load, unless it's been disabled
+    pub fn shared() -> Option<DiskCache> {
+        let directory = DIRECTORY
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        directory.clone().map(|directory| DiskCache {
+            directory,
+            capacity: DiskCache::shared_capacity(),
+        })
+    }
+
+    /// Move the cache used by every load to `directory`, or disable it with `None`
+    pub fn set_shared(directory: Option<PathBuf>) {
+        *DIRECTORY
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = directory;
+    }
+
+    /// Set the most bytes the shared cache's files take up, removing files as it's next written
+    pub fn set_shared_capacity(capacity: u64) {
+        CAPACITY.store(capacity, Ordering::Relaxed);
+    }
+
+    pub fn shared_capacity() -> u64 {
+        CAPACITY.load(Ordering::Relaxed)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+    }
+
+    /// Key of the samples computed from `source` by `transform`, which names the decoder (or
+    /// effect) and its version along with any parameters it was given
+    pub fn key(source: &[u8], transform: &[u8]) -> String {
+        let mut key = Key::default();
+        key.update(source);
+        key.finish(transform)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(key).with_extension("pcm")
+    }
+
+    /// Samples stored under `key`, and their sample rate, unless they're missing or unreadable
+    pub fn get(&self, key: &str) -> Option<(Samples, u32)> {
+        let path = self.path(key);
+        let bytes = fs::read(&path).ok()?;
+        // Marked as recently used, so that it's among the last to be removed
+        if let Ok(file) = fs::File::options().append(true).open(&path) {
+            _ = file.set_modified(SystemTime::now());
+        }
+        let header = bytes.get(..HEADER)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return None;
+        }
+        let (format, channels) = (header[5], header[6]);
+        let sample_rate = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let frames = u64::from_le_bytes(header[12..20].try_into().unwrap()) as usize;
+        let width = match format {
+            0 => 2,
+            1 => 3,
+            2 | 3 => 4,
+            4 => 8,
+            _ => return None,
+        };
+        let data = &bytes[HEADER..];
+        if data.len() != frames.checked_mul(width * channels as usize)? {
+            return None;
+        }
+        let samples = match channels {
+            1 => Samples::Mono(
+                data.chunks_exact(width)
+                    .map(|s| sample(format, [s]))
+                    .collect(),
+            ),
+            2 => Samples::Stereo(
+                data.chunks_exact(width * 2)
+                    .map(|frame| {
+                        let (l, r) = frame.split_at(width);
+                        sample(format, [l, r])
+                    })
+                    .collect(),
+            ),
+            _ => return None,
+        };
+        Some((samples, sample_rate))
+    }
+
+    /// Store `samples` under `key`
+    ///
+    /// Samples are written to a temporary file first, so that a cached file is never seen half
+    /// written. The least recently used files are then removed until the cache is within its
+    /// capacity.
+    pub fn put(&self, key: &str, samples: &Samples, sample_rate: u32) -> io::Result<()> {
+        let (format, channels, frames) = match samples {
+            Samples::Mono(samples) => (samples.first().map_or(0, format), 1, samples.len()),
+            Samples::Stereo(samples) => (samples.first().map_or(0, format), 2, samples.len()),
+        };
+        let mut bytes = Vec::with_capacity(HEADER);
+        bytes.extend(MAGIC);
+        bytes.extend([VERSION, format, channels, 0]);
+        bytes.extend(sample_rate.to_le_bytes());
+        bytes.extend((frames as u64).to_le_bytes());
+        match samples {
+            Samples::Mono(samples) => samples.iter().for_each(|s| write(&mut bytes, s)),
+            Samples::Stereo(samples) => samples.iter().for_each(|s| write(&mut bytes, s)),
+        }
+
+        fs::create_dir_all(&self.directory)?;
+        let path = self.path(key);
+        let write = WRITES.fetch_add(1, Ordering::Relaxed);
+        let temporary = path.with_extension(format!("{}-{write}.tmp", std::process::id()));
+        fs::File::create(&temporary)?.write_all(&bytes)?;
+        fs::rename(&temporary, &path)?;
+        self.shrink()
+    }
+
+    /// Remove the least recently used files until the cache is within its capacity
+    fn shrink(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "pcm") {
+                continue;
+            }
+            // Files removed meanwhile, e.g. by another process shrinking the cache, are skipped
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((used, metadata.len(), path));
+        }
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+        for (_, len, path) in files {
+            if size <= self.capacity {
+                break;
+            }
+            match fs::remove_file(path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => size -= len,
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove every file of the cache
+    pub fn clear(&self) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "pcm") {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Code of the format of a sample in cached files
+fn format<const CHANNELS: usize>(sample: &Sample<CHANNELS>) -> u8 {
+    match sample {
+        Sample::I16(_) => 0,
+        Sample::I24(_) => 1,
+        Sample::I32(_) => 2,
+        Sample::F32(_) => 3,
+        Sample::F64(_) => 4,
+    }
+}
+
+fn write<const CHANNELS: usize>(bytes: &mut Vec<u8>, sample: &Sample<CHANNELS>) {
+    match sample {
+        Sample::I16(s) => s.iter().for_each(|s| bytes.extend(s.to_le_bytes())),
+        Sample::I24(s) => s.iter().for_each(|s| bytes.extend(s)),
+        Sample::I32(s) => s.iter().for_each(|s| bytes.extend(s.to_le_bytes())),
+        Sample::F32(s) => s.iter().for_each(|s| bytes.extend(s.to_le_bytes())),
+        Sample::F64(s) => s.iter().for_each(|s| bytes.extend(s.to_le_bytes())),
+    }
+}
+
+/// A sample of `format` from the bytes of each of its channels
+fn sample<const CHANNELS: usize>(format: u8, channels: [&[u8]; CHANNELS]) -> Sample<CHANNELS> {
+    match format {
+        0 => Sample::I16(channels.map(|s| i16::from_le_bytes(s.try_into().unwrap()))),
+        1 => Sample::I24(channels.map(|s| s.try_into().unwrap())),
+        2 => Sample::I32(channels.map(|s| i32::from_le_bytes(s.try_into().unwrap()))),
+        3 => Sample::F32(channels.map(|s| f32::from_le_bytes(s.try_into().unwrap()))),
+        _ => Sample::F64(channels.map(|s| f64::from_le_bytes(s.try_into().unwrap()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let directory = env::temp_dir().join(format!("disk_cache-{}", std::process::id()));
+        let cache = DiskCache::new(&directory);
+        let stereo = Samples::Stereo(vec![
+            Sample::I24([[1, 2, 3], [4, 5, 6]]),
+            Sample::I24([[7, 8, 9], [10, 11, 12]]),
+        ]);
+        let mono = Samples::Mono(vec![Sample::F32([0.5]), Sample::F32([-0.25])]);
+
+        let (flac, lossy) = (
+            DiskCache::key(b"abc", b"flac"),
+            DiskCache::key(b"abc", b"lossy"),
+        );
+        assert_ne!(flac, lossy);
+        assert_ne!(flac, DiskCache::key(b"abd", b"flac"));
+        // Sources fed piece by piece have the same key
+        let mut key = Key::default();
+        key.update(b"ab");
+        key.update(b"c");
+        assert_eq!(key.finish(b"flac"), flac);
+        assert!(cache.get(&flac).is_none());
+        cache.put(&flac, &stereo, 48_000).unwrap();
+        cache.put(&lossy, &mono, 44_100).unwrap();
+
+        let Some((Samples::Stereo(samples), 48_000)) = cache.get(&flac) else {
+            panic!("expected cached stereo samples");
+        };
+        assert!(matches!(
+            samples[..],
+            [
+                Sample::I24([[1, 2, 3], [4, 5, 6]]),
+                Sample::I24([[7, 8, 9], [10, 11, 12]])
+            ]
+        ));
+        let Some((Samples::Mono(samples), 44_100)) = cache.get(&lossy) else {
+            panic!("expected cached mono samples");
+        };
+        assert!(matches!(
+            samples[..],
+            [Sample::F32([0.5]), Sample::F32([-0.25])]
+        ));
+
+        // Truncated files are ignored, rather than read short
+        let path = cache.path(&flac);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(cache.get(&flac).is_none());
+
+        cache.clear().unwrap();
+        assert!(cache.get(&lossy).is_none());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn removes_least_recently_used() {
+        let directory = env::temp_dir().join(format!("disk_cache-lru-{}", std::process::id()));
+        let mut cache = DiskCache::new(&directory);
+        let samples = Samples::Mono(vec![Sample::I16([0]); 100]);
+        let file = (HEADER + 200) as u64;
+        cache.set_capacity(2 * file);
+
+        let [a, b, c] = [b"a", b"b", b"c"].map(|source| DiskCache::key(source, b"test"));
+        cache.put(&a, &samples, 1_000).unwrap();
+        cache.put(&b, &samples, 1_000).unwrap();
+        // Reading `a` makes `b` the least recently used
+        let past = SystemTime::now() - std::time::Duration::from_secs(60);
+        for key in [&a, &b] {
+            let file = fs::File::options().append(true).open(cache.path(key));
+            file.unwrap().set_modified(past).unwrap();
+        }
+        assert!(cache.get(&a).is_some());
+        cache.put(&c, &samples, 1_000).unwrap();
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some());
+
+        // No temporary files are left behind
+        let files = fs::read_dir(&directory).unwrap().count();
+        assert_eq!(files, 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Effects built on delay lines: echoes and reverb
+
+use crate::Process;
+
+/// Decays of a signal to fall below -60dB, ln(1000)
+const DECAYS: f32 = 6.908;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Delay {
+    /// Seconds between echoes
+    pub time: f32,
+    /// Level of each echo relative to the last, below `1.0`
+    pub feedback: f32,
+    /// Level of the echoes relative to the dry audio
+    pub mix: f32,
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Delay {
+            time: 0.25,
+            feedback: 0.4,
+            mix: 0.3,
+        }
+    }
+}
+
+/// A ring buffer of stereo frames
+struct Line {
+    frames: Vec<[f32; 2]>,
+    position: usize,
+}
+
+impl Line {
+    fn new(len: usize) -> Self {
+        Line {
+            frames: vec![[0.; 2]; len.max(1)],
+            position: 0,
+        }
+    }
+
+    /// Frame written `len` frames ago
+    fn read(&self) -> [f32; 2] {
+        self.frames[self.position]
+    }
+
+    fn write(&mut self, frame: [f32; 2]) {
+        self.frames[self.position] = frame;
+        self.position = (self.position + 1) % self.frames.len();
+    }
+}
+
+pub(crate) struct DelayLine {
+    delay: Delay,
+    line: Line,
+}
+
+impl DelayLine {
+    pub(crate) fn new(delay: &Delay, sample_rate: f32) -> Self {
+        let feedback = delay.feedback.clamp(0., 0.999);
+        DelayLine {
+            delay: Delay { feedback, ..*delay },
+            line: Line::new((delay.time * sample_rate).round() as usize),
+        }
+    }
+}
+
+impl Process for DelayLine {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let Delay { feedback, mix, .. } = self.delay;
+        let echo = self.line.read();
+        self.line
+            .write([0, 1].map(|c| frame[c] + feedback * echo[c]));
+        [0, 1].map(|c| frame[c] + mix * echo[c])
+    }
+
+    fn tail(&self) -> f32 {
+        let echoes = match self.delay.feedback {
+            0. => 1.,
+            feedback => DECAYS / -feedback.ln() + 1.,
+        };
+        self.delay.time * echoes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Reverb {
+    /// Seconds for the reverb to decay by 60dB
+    pub decay: f32,
+    /// How quickly high frequencies decay, from `0.0` to `1.0`
+    pub damping: f32,
+    /// Balance between the reverb (`1.0`) and dry (`0.0`) audio
+    pub mix: f32,
+}
+
+impl Default for Reverb {
+    fn default() -> Self {
+        Reverb {
+            decay: 1.5,
+            damping: 0.5,
+            mix: 0.25,
+        }
+    }
+}
+
+/// Lengths of the comb filters at 44.1kHz
+const COMBS: [usize; 4] = [1116, 1188, 1277, 1356];
+/// Lengths of the all-pass filters at 44.1kHz
+const ALLPASSES: [usize; 2] = [556, 441];
+/// Frames the right channel's lines are longer than the left's
+const SPREAD: usize = 23;
+
+/// A feedback comb filter, low-passed in the loop
+struct Comb {
+    line: Vec<f32>,
+    position: usize,
+    feedback: f32,
+    low: f32,
+}
+
+/// A Schroeder all-pass filter
+struct Allpass {
+    line: Vec<f32>,
+    position: usize,
+}
+
+/// A Freeverb-style reverb: parallel combs into all-passes, with one set per channel
+pub(crate) struct Freeverb {
+    reverb: Reverb,
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl Freeverb {
+    pub(crate) fn new(reverb: &Reverb, sample_rate: f32) -> Self {
+        let scale = |len: usize| ((len as f32 * sample_rate / 44_100.).round() as usize).max(1);
+        let decay = reverb.decay.max(0.01) * sample_rate;
+        let channel = |spread: usize| {
+            let combs = COMBS
+                .map(|len| {
+                    let len = scale(len + spread);
+                    Comb {
+                        line: vec![0.; len],
+                        position: 0,
+                        // Falls by 60dB every `decay` frames
+                        feedback: 10f32.powf(-3. * len as f32 / decay),
+                        low: 0.,
+                    }
+                })
+                .into();
+            let allpasses = ALLPASSES
+                .map(|len| Allpass {
+                    line: vec![0.; scale(len + spread)],
+                    position: 0,
+                })
+                .into();
+            (combs, allpasses)
+        };
+        let ((left_combs, left_allpasses), (right_combs, right_allpasses)) =
+            (channel(0), channel(SPREAD));
+        Freeverb {
+            reverb: *reverb,
+            combs: [left_combs, right_combs],
+            allpasses: [left_allpasses, right_allpasses],
+        }
+    }
+}
+
+impl Process for Freeverb {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let Reverb { damping, mix, .. } = self.reverb;
+        let input = (frame[0] + frame[1]) / 2.;
+        [0, 1].map(|c| {
+            let mut wet = 0.;
+            for comb in &mut self.combs[c] {
+                let out = comb.line[comb.position];
+                comb.low = out * (1. - damping) + comb.low * damping;
+                comb.line[comb.position] = input + comb.low * comb.feedback;
+                comb.position = (comb.position + 1) % comb.line.len();
+                wet += out / COMBS.len() as f32;
+            }
+            for allpass in &mut self.allpasses[c] {
+                let delayed = allpass.line[allpass.position];
+                allpass.line[allpass.position] = wet + delayed / 2.;
+                allpass.position = (allpass.position + 1) % allpass.line.len();
+                wet = delayed - wet;
+            }
+            (1. - mix) * frame[c] + mix * wet
+        })
+    }
+
+    fn tail(&self) -> f32 {
+        self.reverb.decay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reverb_decays() {
+        let mut reverb = Freeverb::new(
+            &Reverb {
+                decay: 1.,
+                damping: 0.,
+                mix: 1.,
+            },
+            8_000.,
+        );
+        let mut samples = vec![reverb.process([1.; 2])[0]];
+        samples.extend((1..16_000).map(|_| reverb.process([0.; 2])[0]));
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |peak, s| s.abs().max(peak));
+        let (early, late) = (peak(&samples[..2_000]), peak(&samples[8_000..10_000]));
+        assert!(early > 0.01);
+        assert!(late < early / 100.);
+    }
+}
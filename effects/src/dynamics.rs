@@ -0,0 +1,136 @@
+//! Effects on the level of audio: gain, distortion and compression
+
+use types::{amplitude, decibels};
+
+use crate::Process;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Gain {
+    pub db: f32,
+}
+
+impl Process for Gain {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let gain = amplitude(self.db);
+        frame.map(|s| s * gain)
+    }
+}
+
+/// Soft clipping through `tanh`, keeping full scale at full scale
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Distortion {
+    /// Gain in decibels driving the audio into the clipper
+    pub drive: f32,
+    /// Balance between the distorted (`1.0`) and dry (`0.0`) audio
+    pub mix: f32,
+}
+
+impl Default for Distortion {
+    fn default() -> Self {
+        Distortion {
+            drive: 12.,
+            mix: 1.,
+        }
+    }
+}
+
+impl Process for Distortion {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let drive = amplitude(self.drive);
+        frame.map(|s| (1. - self.mix) * s + self.mix * (s * drive).tanh() / drive.tanh())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Compressor {
+    /// Level in decibels above which the audio is compressed
+    pub threshold: f32,
+    /// Decibels in over the threshold for every decibel out
+    pub ratio: f32,
+    /// Seconds taken to react to the level rising
+    pub attack: f32,
+    /// Seconds taken to react to the level falling
+    pub release: f32,
+    /// Gain in decibels applied after compression
+    pub makeup: f32,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Compressor {
+            threshold: -18.,
+            ratio: 4.,
+            attack: 0.01,
+            release: 0.1,
+            makeup: 0.,
+        }
+    }
+}
+
+/// A compressor following the peak level of both channels
+pub(crate) struct Follower {
+    compressor: Compressor,
+    attack: f32,
+    release: f32,
+    /// Smoothed level in decibels
+    level: f32,
+}
+
+impl Follower {
+    pub(crate) fn new(compressor: &Compressor, sample_rate: f32) -> Self {
+        let coefficient = |time: f32| (-1. / (time * sample_rate).max(1.)).exp();
+        Follower {
+            compressor: *compressor,
+            attack: coefficient(compressor.attack),
+            release: coefficient(compressor.release),
+            level: decibels(0.),
+        }
+    }
+}
+
+impl Process for Follower {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let Compressor {
+            threshold,
+            ratio,
+            makeup,
+            ..
+        } = self.compressor;
+        let peak = decibels(frame[0].abs().max(frame[1].abs()));
+        let coefficient = if peak > self.level {
+            self.attack
+        } else {
+            self.release
+        };
+        self.level = peak + coefficient * (self.level - peak);
+
+        let over = (self.level - threshold).max(0.);
+        let gain = amplitude(makeup - over * (1. - 1. / ratio.max(1.)));
+        frame.map(|s| s * gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressor_reduces_loud_audio() {
+        let mut follower = Follower::new(
+            &Compressor {
+                threshold: -20.,
+                ratio: 4.,
+                ..Default::default()
+            },
+            1_000.,
+        );
+        let quiet = (0..1_000).map(|_| follower.process([0.05; 2])[0]).last();
+        let loud = (0..1_000).map(|_| follower.process([1.; 2])[0]).last();
+        // -26dB is left alone, while 0dB is 20dB over and brought down to 5dB over
+        assert!((quiet.unwrap() - 0.05).abs() < 1e-3);
+        assert!((loud.unwrap() - amplitude(-15.)).abs() < 1e-3);
+    }
+}
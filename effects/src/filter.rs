@@ -0,0 +1,169 @@
+//! Biquad filters, after the Audio EQ Cookbook
+
+use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+
+use crate::Process;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Cutoff, centre or corner frequency in Hz
+    pub frequency: f32,
+    /// Resonance, or the narrowness of a band
+    pub q: f32,
+    /// Gain in decibels of shelves and peaks
+    pub db: f32,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            frequency: 1_000.,
+            q: FRAC_1_SQRT_2,
+            db: 0.,
+        }
+    }
+}
+
+/// A second-order filter in transposed direct form II
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    /// State of each channel
+    z: [[f32; 2]; 2],
+}
+
+/// Cosine of the angular frequency of a filter, and its bandwidth term
+fn terms(filter: &Filter, sample_rate: f32) -> (f32, f32) {
+    let w0 = TAU * filter.frequency.clamp(1., sample_rate / 2. - 1.) / sample_rate;
+    (w0.cos(), w0.sin() / (2. * filter.q.max(0.01)))
+}
+
+/// Square root of the linear gain of shelves and peaks
+fn shelf_gain(filter: &Filter) -> f32 {
+    10f32.powf(filter.db / 40.)
+}
+
+impl Biquad {
+    /// Normalize the coefficients by `a0`
+    fn new([b0, b1, b2]: [f32; 3], [a0, a1, a2]: [f32; 3]) -> Self {
+        Biquad {
+            b: [b0 / a0, b1 / a0, b2 / a0],
+            a: [a1 / a0, a2 / a0],
+            z: [[0.; 2]; 2],
+        }
+    }
+
+    pub fn lowpass(filter: &Filter, sample_rate: f32) -> Self {
+        let (cos, alpha) = terms(filter, sample_rate);
+        let b = (1. - cos) / 2.;
+        Biquad::new([b, 1. - cos, b], [1. + alpha, -2. * cos, 1. - alpha])
+    }
+
+    pub fn highpass(filter: &Filter, sample_rate: f32) -> Self {
+        let (cos, alpha) = terms(filter, sample_rate);
+        let b = (1. + cos) / 2.;
+        Biquad::new([b, -1. - cos, b], [1. + alpha, -2. * cos, 1. - alpha])
+    }
+
+    /// A band-pass with a peak gain of 0dB
+    pub fn bandpass(filter: &Filter, sample_rate: f32) -> Self {
+        let (cos, alpha) = terms(filter, sample_rate);
+        Biquad::new([alpha, 0., -alpha], [1. + alpha, -2. * cos, 1. - alpha])
+    }
+
+    pub fn peak(filter: &Filter, sample_rate: f32) -> Self {
+        let (cos, alpha) = terms(filter, sample_rate);
+        let a = shelf_gain(filter);
+        Biquad::new(
+            [1. + alpha * a, -2. * cos, 1. - alpha * a],
+            [1. + alpha / a, -2. * cos, 1. - alpha / a],
+        )
+    }
+
+    pub fn lowshelf(filter: &Filter, sample_rate: f32) -> Self {
+        let (cos, alpha) = terms(filter, sample_rate);
+        let a = shelf_gain(filter);
+        let s = 2. * a.sqrt() * alpha;
+        Biquad::new(
+            [
+                a * ((a + 1.) - (a - 1.) * cos + s),
+                2. * a * ((a - 1.) - (a + 1.) * cos),
+                a * ((a + 1.) - (a - 1.) * cos - s),
+            ],
+            [
+                (a + 1.) + (a - 1.) * cos + s,
+                -2. * ((a - 1.) + (a + 1.) * cos),
+                (a + 1.) + (a - 1.) * cos - s,
+            ],
+        )
+    }
+
+    pub fn highshelf(filter: &Filter, sample_rate: f32) -> Self {
+        let (cos, alpha) = terms(filter, sample_rate);
+        let a = shelf_gain(filter);
+        let s = 2. * a.sqrt() * alpha;
+        Biquad::new(
+            [
+                a * ((a + 1.) + (a - 1.) * cos + s),
+                -2. * a * ((a - 1.) + (a + 1.) * cos),
+                a * ((a + 1.) + (a - 1.) * cos - s),
+            ],
+            [
+                (a + 1.) - (a - 1.) * cos + s,
+                2. * ((a - 1.) - (a + 1.) * cos),
+                (a + 1.) - (a - 1.) * cos - s,
+            ],
+        )
+    }
+}
+
+impl Process for Biquad {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let ([b0, b1, b2], [a1, a2]) = (self.b, self.a);
+        let mut out = [0.; 2];
+        for (channel, x) in frame.into_iter().enumerate() {
+            let z = &mut self.z[channel];
+            let y = b0 * x + z[0];
+            z[0] = b1 * x - a1 * y + z[1];
+            z[1] = b2 * x - a2 * y;
+            out[channel] = y;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Peak level of the second half of a sine at `frequency` through `biquad`
+    fn response(mut biquad: Biquad, frequency: f32) -> f32 {
+        (0..8_000)
+            .map(|i| biquad.process([(TAU * frequency * i as f32 / 8_000.).sin(); 2])[0])
+            .skip(4_000)
+            .fold(0., |peak, s| s.abs().max(peak))
+    }
+
+    #[test]
+    fn filters_respond() {
+        let filter = Filter {
+            frequency: 500.,
+            ..Default::default()
+        };
+        let lowpass = Biquad::lowpass(&filter, 8_000.);
+        assert!(response(lowpass, 50.) > 0.95);
+        assert!(response(lowpass, 3_000.) < 0.05);
+
+        let highpass = Biquad::highpass(&filter, 8_000.);
+        assert!(response(highpass, 50.) < 0.05);
+        assert!(response(highpass, 3_000.) > 0.95);
+
+        let boost = Filter { db: 6., ..filter };
+        let peak = Biquad::peak(&boost, 8_000.);
+        assert!((response(peak, 500.) - 2.).abs() < 0.05);
+        assert!((response(Biquad::lowshelf(&boost, 8_000.), 20.) - 2.).abs() < 0.05);
+        assert!((response(Biquad::highshelf(&boost, 8_000.), 20.) - 1.).abs() < 0.05);
+    }
+}
@@ -0,0 +1,460 @@
+//! `effects`, instruments processing the audio of another instrument
+
+mod delay;
+mod dynamics;
+mod filter;
+pub use delay::{Delay, Reverb};
+pub use dynamics::{Compressor, Distortion, Gain};
+pub use filter::Filter;
+
+use std::{fmt, sync::Arc};
+
+use filter::Biquad;
+
+use disk_cache::{DiskCache, Key, Samples};
+use types::*;
+
+#[derive(Debug)]
+pub enum EffectError {
+    /// The source's length isn't known, so it can't be played through
+    LengthUnknown(String),
+}
+
+impl fmt::Display for EffectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EffectError::LengthUnknown(name) => write!(
+                f,
+                "Instrument \"{name}\" can't be played through an effect as its length is unknown"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EffectError {}
+
+impl From<EffectError> for LuaError {
+    fn from(value: EffectError) -> Self {
+        LuaError::ExternalError(Arc::new(value))
+    }
+}
+
+/// Processes a stream of stereo frames, one after another
+trait Process {
+    fn process(&mut self, frame: [f32; 2]) -> [f32; 2];
+
+    /// Seconds the effect rings on for after its input has ended
+    fn tail(&self) -> f32 {
+        0.
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EffectConfig {
+    Gain(Gain),
+    Lowpass(Filter),
+    Highpass(Filter),
+    Bandpass(Filter),
+    Lowshelf(Filter),
+    Highshelf(Filter),
+    Peak(Filter),
+    Delay(Delay),
+    Reverb(Reverb),
+    Distortion(Distortion),
+    Compressor(Compressor),
+}
+
+impl FromLua for EffectConfig {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        use serde::Deserialize as _;
+        EffectConfig::deserialize(LuaDeserializer::new(value))
+    }
+}
+
+impl EffectConfig {
+    fn name(&self) -> &'static str {
+        match self {
+            EffectConfig::Gain(_) => "gain",
+            EffectConfig::Lowpass(_) => "lowpass",
+            EffectConfig::Highpass(_) => "highpass",
+            EffectConfig::Bandpass(_) => "bandpass",
+            EffectConfig::Lowshelf(_) => "lowshelf",
+            EffectConfig::Highshelf(_) => "highshelf",
+            EffectConfig::Peak(_) => "peak",
+            EffectConfig::Delay(_) => "delay",
+            EffectConfig::Reverb(_) => "reverb",
+            EffectConfig::Distortion(_) => "distortion",
+            EffectConfig::Compressor(_) => "compressor",
+        }
+    }
+
+    /// Every parameter of the effect, in a fixed order
+    fn params(&self) -> Vec<f32> {
+        match *self {
+            EffectConfig::Gain(Gain { db }) => vec![db],
+            EffectConfig::Lowpass(Filter { frequency, q, db })
+            | EffectConfig::Highpass(Filter { frequency, q, db })
+            | EffectConfig::Bandpass(Filter { frequency, q, db })
+            | EffectConfig::Lowshelf(Filter { frequency, q, db })
+            | EffectConfig::Highshelf(Filter { frequency, q, db })
+            | EffectConfig::Peak(Filter { frequency, q, db }) => vec![frequency, q, db],
+            EffectConfig::Delay(Delay {
+                time,
+                feedback,
+                mix,
+            }) => vec![time, feedback, mix],
+            EffectConfig::Reverb(Reverb {
+                decay,
+                damping,
+                mix,
+            }) => vec![decay, damping, mix],
+            EffectConfig::Distortion(Distortion { drive, mix }) => vec![drive, mix],
+            EffectConfig::Compressor(Compressor {
+                threshold,
+                ratio,
+                attack,
+                release,
+                makeup,
+            }) => vec![threshold, ratio, attack, release, makeup],
+        }
+    }
+
+    /// Names the effect at `sample_rate` in the keys of the [`DiskCache`], as its name and
+    /// parameters laid out in version [`CACHE_VERSION`]
+    fn transform(&self, sample_rate: u32) -> Vec<u8> {
+        let mut bytes = b"effects".to_vec();
+        bytes.extend(CACHE_VERSION.to_le_bytes());
+        bytes.extend(self.name().as_bytes());
+        bytes.push(0);
+        bytes.extend(sample_rate.to_le_bytes());
+        for param in self.params() {
+            bytes.extend(param.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn processor(&self, sample_rate: u32) -> Box<dyn Process> {
+        let sample_rate = sample_rate as f32;
+        match self {
+            EffectConfig::Gain(gain) => Box::new(*gain),
+            EffectConfig::Lowpass(filter) => Box::new(Biquad::lowpass(filter, sample_rate)),
+            EffectConfig::Highpass(filter) => Box::new(Biquad::highpass(filter, sample_rate)),
+            EffectConfig::Bandpass(filter) => Box::new(Biquad::bandpass(filter, sample_rate)),
+            EffectConfig::Lowshelf(filter) => Box::new(Biquad::lowshelf(filter, sample_rate)),
+            EffectConfig::Highshelf(filter) => Box::new(Biquad::highshelf(filter, sample_rate)),
+            EffectConfig::Peak(filter) => Box::new(Biquad::peak(filter, sample_rate)),
+            EffectConfig::Delay(delay) => Box::new(delay::DelayLine::new(delay, sample_rate)),
+            EffectConfig::Reverb(reverb) => Box::new(delay::Freeverb::new(reverb, sample_rate)),
+            EffectConfig::Distortion(distortion) => Box::new(*distortion),
+            EffectConfig::Compressor(compressor) => {
+                Box::new(dynamics::Follower::new(compressor, sample_rate))
+            }
+        }
+    }
+}
+
+/// Sample rate assumed of instruments that don't have one
+const SAMPLE_RATE: u32 = 44_100;
+
+/// Frames read from the source instrument at a time
+const BLOCK: usize = 4096;
+
+/// Version of how effects are keyed in the [`DiskCache`], bumped whenever
+/// [`EffectConfig::transform`] or how any effect processes its frames changes
+const CACHE_VERSION: u32 = 1;
+
+/// Most seconds an effect rings on for after its source has ended
+const MAX_TAIL: f32 = 10.;
+
+/// An instrument played through an effect
+///
+/// Effects carry state from one frame to the next, so the source is played through once up front,
+/// followed by the effect's tail, rather than on every `get`. The source has to know its length,
+/// as a looping one would otherwise play on forever.
+///
+/// Effects made from Lua are kept in the shared [`DiskCache`], keyed by the source's frames and
+/// the effect's parameters, so that they aren't played through again once Plunder restarts.
+pub struct Effect {
+    name: String,
+    channels: u16,
+    sample_rate: Option<u32>,
+    loop_points: Option<(u32, u32)>,
+    frames: Vec<[f32; 2]>,
+}
+
+impl Effect {
+    pub fn new(source: &dyn BiInstrument, config: &EffectConfig) -> Result<Self, EffectError> {
+        Effect::cached(source, config, None)
+    }
+
+    /// [`Effect::new`], reusing the frames `cache` holds of the same source through the same
+    /// effect, or else storing them there
+    pub fn cached(
+        source: &dyn BiInstrument,
+        config: &EffectConfig,
+        cache: Option<&DiskCache>,
+    ) -> Result<Self, EffectError> {
+        let length = source
+            .length()
+            .ok_or_else(|| EffectError::LengthUnknown(source.name()))?;
+        let sample_rate = source.sample_rate().unwrap_or(SAMPLE_RATE);
+        let mut processor = config.processor(sample_rate);
+
+        let mut frames = read(source, length as usize);
+        // Long tails are cut short, such as that of a delay with feedback close to 1 (which is as
+        // high as it's clamped to). A tail that isn't a duration, being negative or NaN as from a
+        // negative delay time, is made the longest so that nothing is cut off
+        let tail = match processor.tail() {
+            tail if tail >= 0. => tail.min(MAX_TAIL),
+            _ => MAX_TAIL,
+        };
+        let tail = (tail * sample_rate as f32).ceil() as usize;
+
+        let cache = cache.map(|cache| {
+            let mut key = Key::default();
+            for s in frames.iter().flatten() {
+                key.update(&s.to_le_bytes());
+            }
+            (cache, key.finish(&config.transform(sample_rate)))
+        });
+        match &cache {
+            Some((cache, key)) if let Some((Samples::Stereo(cached), _)) = cache.get(key) => {
+                frames = cached.iter().map(Sample::to_f32).collect();
+            }
+            _ => {
+                frames.resize(frames.len() + tail, [0.; 2]);
+                for frame in &mut frames {
+                    *frame = processor.process(*frame);
+                }
+                if let Some((cache, key)) = cache {
+                    let samples = frames.iter().map(|frame| Sample::F32(*frame)).collect();
+                    // Failing to store the frames only means they're played through again
+                    _ = cache.put(&key, &Samples::Stereo(samples), sample_rate);
+                }
+            }
+        }
+
+        Ok(Effect {
+            name: format!("{}({})", config.name(), source.name()),
+            channels: source.channels(),
+            sample_rate: source.sample_rate(),
+            // Looping back from the end of the source would cut off the tail
+            loop_points: source.loop_points().filter(|_| tail == 0),
+            frames,
+        })
+    }
+}
+
+/// Play the first `length` frames of `source` as stereo frames
+fn read(source: &dyn BiInstrument, length: usize) -> Vec<[f32; 2]> {
+    let stereo = Instrument::<2>::ok(source).is_ok();
+    let mut frames = Vec::with_capacity(length);
+    let (mut mono_block, mut stereo_block) = (
+        vec![Sample::F32([0.]); BLOCK],
+        vec![Sample::F32([0.; 2]); BLOCK],
+    );
+    while frames.len() < length {
+        let start = frames.len() as u32;
+        let wanted = BLOCK.min(length - frames.len());
+        let n = if stereo {
+            let n = Instrument::<2>::fill(source, start, &mut stereo_block[..wanted]);
+            frames.extend(stereo_block[..n].iter().map(Sample::to_f32));
+            n
+        } else {
+            let n = Instrument::<1>::fill(source, start, &mut mono_block[..wanted]);
+            frames.extend(mono_block[..n].iter().map(|s| [s.to_f32()[0]; 2]));
+            n
+        };
+        if n < wanted {
+            break;
+        }
+    }
+    frames
+}
+
+impl Instrument<1> for Effect {
+    fn ok(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        let [l, r] = *self.frames.get(id as usize)?;
+        Some(Sample::F32([(l + r) / 2.]))
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        fill_from_slice(&self.frames, start, frames, |[l, r]| {
+            Sample::F32([(l + r) / 2.])
+        })
+    }
+}
+
+impl Instrument<2> for Effect {
+    fn ok(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        self.frames
+            .get(id as usize)
+            .map(|frame| Sample::F32(*frame))
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        fill_from_slice(&self.frames, start, frames, Sample::F32)
+    }
+}
+
+impl BiInstrument for Effect {
+    fn loop_points(&self) -> Option<(u32, u32)> {
+        self.loop_points
+    }
+
+    fn length(&self) -> Option<u32> {
+        Some(self.frames.len() as u32)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+impl LuaUserData for Effect {}
+
+impl InstrumentFactory for Effect {
+    type Args = (LuaUserDataRef<Box<dyn BiInstrument>>, EffectConfig);
+    type Instrument = Effect;
+    const NAME: &str = "effect";
+
+    fn construct((source, config): Self::Args) -> LuaResult<Option<Effect>> {
+        Ok(Some(Effect::cached(
+            &**source,
+            &config,
+            DiskCache::shared().as_ref(),
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::test_util::TestInstrument;
+
+    use super::*;
+
+    /// A mono instrument looping `samples` at 1kHz
+    fn looping(samples: Vec<f32>) -> TestInstrument {
+        let length = samples.len() as u32;
+        TestInstrument {
+            length: Some(length),
+            loop_points: Some((0, length)),
+            sample_rate: Some(1_000),
+            name: "samples",
+            ..TestInstrument::new(samples)
+        }
+    }
+
+    fn left(effect: &Effect) -> Vec<f32> {
+        effect.frames.iter().map(|[l, _]| *l).collect()
+    }
+
+    #[test]
+    fn delay_echoes() {
+        let mut impulse = vec![0.; 10];
+        impulse[0] = 1.;
+        let effect = Effect::new(
+            &looping(impulse),
+            &EffectConfig::Delay(Delay {
+                time: 0.1,
+                feedback: 0.5,
+                mix: 1.,
+            }),
+        )
+        .unwrap();
+        let samples = left(&effect);
+        assert_eq!(effect.name(), "delay(samples)");
+        assert_eq!(effect.channels(), 1);
+        assert_eq!((samples[0], samples[100], samples[200]), (1., 1., 0.5));
+        assert_eq!(samples[50], 0.);
+        // Rings on until the echoes have decayed below -60dB, and so doesn't loop
+        assert!(samples.len() > 1_000);
+        assert_eq!(effect.loop_points(), None);
+
+        // Echoes which never die down ring on for as long as tails are capped to
+        let effect = Effect::new(
+            &looping(vec![1.; 10]),
+            &EffectConfig::Delay(Delay {
+                time: 0.1,
+                feedback: 1.,
+                mix: 1.,
+            }),
+        )
+        .unwrap();
+        assert_eq!(effect.length(), Some(10 + MAX_TAIL as u32 * 1_000));
+    }
+
+    #[test]
+    fn gain_scales() {
+        let effect = Effect::new(
+            &looping(vec![0.5, -0.25]),
+            &EffectConfig::Gain(Gain { db: -6.0206 }),
+        )
+        .unwrap();
+        let samples = left(&effect);
+        assert!((samples[0] - 0.25).abs() < 1e-4);
+        assert!((samples[1] + 0.125).abs() < 1e-4);
+        assert_eq!(effect.loop_points(), Some((0, 2)));
+    }
+
+    #[test]
+    fn cached_on_disk() {
+        let directory = std::env::temp_dir().join(format!("effects-disk-{}", std::process::id()));
+        let cache = DiskCache::new(&directory);
+        let config = EffectConfig::Delay(Delay {
+            time: 0.1,
+            feedback: 0.5,
+            mix: 1.,
+        });
+        let mut impulse = vec![0.; 10];
+        impulse[0] = 1.;
+
+        let played = Effect::cached(&looping(impulse.clone()), &config, Some(&cache)).unwrap();
+        let files = || std::fs::read_dir(&directory).unwrap().count();
+        assert_eq!(files(), 1);
+        let cached = Effect::cached(&looping(impulse.clone()), &config, Some(&cache)).unwrap();
+        assert_eq!(cached.frames, played.frames);
+        assert_eq!(cached.loop_points(), None);
+        assert_eq!(files(), 1);
+
+        // Other sources and parameters are played through again
+        impulse[1] = 1.;
+        Effect::cached(&looping(impulse.clone()), &config, Some(&cache)).unwrap();
+        Effect::cached(
+            &looping(impulse),
+            &EffectConfig::Gain(Gain { db: 1. }),
+            Some(&cache),
+        )
+        .unwrap();
+        assert_eq!(files(), 3);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn unknown_length() {
+        assert!(matches!(
+            Effect::new(
+                &TestInstrument::drone(),
+                &EffectConfig::Gain(Gain { db: 0. })
+            ),
+            Err(EffectError::LengthUnknown(_))
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+//! AIFF and AIFC files, uncompressed or byte-swapped (`sowt`), with their `MARK` and `INST` loops
+
+use std::collections::HashMap;
+
+use types::Sample;
+
+use crate::{
+    Cue, Inner, Markers, WavError,
+    chunks::{chunks, u32_be_at},
+};
+
+/// Sample rate stored as an 80-bit extended-precision float
+fn extended(bytes: [u8; 10]) -> f64 {
+    let exponent = (u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7fff) as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0.;
+    }
+    let value = mantissa as f64 * 2f64.powi(exponent - 16_383 - 63);
+    if bytes[0] & 0x80 != 0 { -value } else { value }
+}
+
+/// Text of a Pascal-style string, and the bytes it takes up padded to an even length
+fn pstring(bytes: &[u8]) -> Option<(String, usize)> {
+    let len = *bytes.first()? as usize;
+    let text = String::from_utf8_lossy(bytes.get(1..1 + len)?).into_owned();
+    Some((text, (1 + len).next_multiple_of(2)))
+}
+
+/// The `COMM` chunk
+struct Common {
+    channels: u16,
+    frames: usize,
+    depth: u16,
+    sample_rate: u32,
+    little_endian: bool,
+}
+
+impl Common {
+    fn parse(data: &[u8], aifc: bool) -> Result<Self, WavError> {
+        let malformed = || WavError::MalformedChunk("COMM");
+        let header: &[u8; 18] = data.get(..18).ok_or_else(malformed)?.try_into().unwrap();
+        let little_endian = match aifc {
+            false => false,
+            true => match data.get(18..22).ok_or_else(malformed)? {
+                b"NONE" | b"twos" => false,
+                b"sowt" => true,
+                other => {
+                    return Err(WavError::UnsupportedCompression(
+                        String::from_utf8_lossy(other).into_owned(),
+                    ));
+                }
+            },
+        };
+        let depth = u16::from_be_bytes([header[6], header[7]]);
+        if !(1..=32).contains(&depth) {
+            return Err(WavError::UnsupportedBitDepth(depth));
+        }
+        Ok(Common {
+            channels: u16::from_be_bytes([header[0], header[1]]),
+            frames: u32_be_at(header, 2).unwrap() as usize,
+            depth,
+            sample_rate: extended(header[8..18].try_into().unwrap()).round() as u32,
+            little_endian,
+        })
+    }
+
+    /// Each sample of the `SSND` chunk's audio
+    fn samples(&self, audio: &[u8]) -> Result<Vec<Sample<1>>, WavError> {
+        let malformed = || WavError::MalformedChunk("SSND");
+        let width = self.depth.div_ceil(8) as usize;
+        let len = self
+            .frames
+            .checked_mul(self.channels as usize)
+            .and_then(|len| len.checked_mul(width))
+            .ok_or_else(malformed)?;
+        let audio = audio.get(..len).ok_or_else(malformed)?;
+        audio
+            .chunks_exact(width)
+            .map(|sample| {
+                // Samples are left-justified big-endian, unless byte-swapped
+                let mut bytes = [0; 4];
+                bytes[..width].copy_from_slice(sample);
+                if self.little_endian {
+                    bytes[..width].reverse();
+                }
+                Ok(match width {
+                    1 => Sample::I16([(bytes[0] as i8 as i16) << 8]),
+                    2 => Sample::I16([i16::from_be_bytes([bytes[0], bytes[1]])]),
+                    3 => Sample::I24([[bytes[0], bytes[1], bytes[2]]]),
+                    4 => Sample::I32([i32::from_be_bytes(bytes)]),
+                    _ => return Err(WavError::UnsupportedBitDepth(self.depth)),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Markers of the `MARK` chunk
+fn marks(data: &[u8]) -> Result<Vec<Cue>, WavError> {
+    let malformed = || WavError::MalformedChunk("MARK");
+    let n = u16::from_be_bytes(data.get(..2).ok_or_else(malformed)?.try_into().unwrap());
+    let mut offset = 2;
+    (0..n)
+        .map(|_| {
+            let id = i16::from_be_bytes(
+                data.get(offset..offset + 2)
+                    .ok_or_else(malformed)?
+                    .try_into()
+                    .unwrap(),
+            );
+            let position = u32_be_at(data, offset + 2).ok_or_else(malformed)?;
+            let (label, len) =
+                pstring(data.get(offset + 6..).unwrap_or_default()).ok_or_else(malformed)?;
+            offset += 6 + len;
+            Ok(Cue {
+                id: id as u32,
+                position,
+                label: (!label.is_empty()).then_some(label),
+                length: None,
+            })
+        })
+        .collect()
+}
+
+/// The sustain and release loops of the `INST` chunk, as the ids of their start and end markers
+fn instrument_loops(data: &[u8]) -> Result<Vec<(u32, u32)>, WavError> {
+    let malformed = || WavError::MalformedChunk("INST");
+    let data = data.get(..20).ok_or_else(malformed)?;
+    let ids = |offset: usize| {
+        let field = |at: usize| i16::from_be_bytes([data[at], data[at + 1]]);
+        // A play mode of 0 means no loop
+        (field(offset) != 0).then(|| (field(offset + 2) as u32, field(offset + 4) as u32))
+    };
+    Ok([ids(8), ids(14)].into_iter().flatten().collect())
+}
+
+impl Inner {
+    /// Parse a whole AIFF or AIFC file, along with its sample rate and markers
+    pub fn parse_aiff(bytes: &[u8]) -> Result<(Self, u32, Markers), WavError> {
+        let aifc = match (bytes.get(..4), bytes.get(8..12)) {
+            (Some(b"FORM"), Some(b"AIFF")) => false,
+            (Some(b"FORM"), Some(b"AIFC")) => true,
+            _ => return Err(WavError::MalformedChunk("FORM")),
+        };
+
+        let (mut common, mut audio) = (None, None);
+        let (mut cues, mut loops) = (Vec::new(), Vec::new());
+        for (id, data) in chunks(&bytes[12..], true) {
+            match &id {
+                b"COMM" => common = Some(Common::parse(data, aifc)?),
+                b"SSND" => {
+                    let offset = u32_be_at(data, 0).ok_or(WavError::MalformedChunk("SSND"))?;
+                    audio = data.get(8 + offset as usize..);
+                }
+                b"MARK" => cues = marks(data)?,
+                b"INST" => loops = instrument_loops(data)?,
+                _ => {}
+            }
+        }
+        let common = common.ok_or(WavError::MalformedChunk("COMM"))?;
+        let samples = common.samples(audio.unwrap_or_default())?;
+
+        let inner = match common.channels {
+            1 => Inner::Mono(samples),
+            2 => Inner::Stereo(
+                samples
+                    .chunks_exact(2)
+                    .map(|frame| match (frame[0], frame[1]) {
+                        (Sample::I16([l]), Sample::I16([r])) => Sample::I16([l, r]),
+                        (Sample::I24([l]), Sample::I24([r])) => Sample::I24([l, r]),
+                        (Sample::I32([l]), Sample::I32([r])) => Sample::I32([l, r]),
+                        _ => unreachable!(),
+                    })
+                    .collect(),
+            ),
+            n => return Err(WavError::UnsupportedNumChannels(n)),
+        };
+
+        let positions: HashMap<_, _> = cues.iter().map(|cue| (cue.id, cue.position)).collect();
+        let loops = loops
+            .into_iter()
+            .filter_map(|(start, end)| Some((*positions.get(&start)?, *positions.get(&end)?)))
+            .filter(|(start, end)| end > start)
+            .collect();
+        cues.sort_by_key(|cue| cue.position);
+        let markers = Markers {
+            loops,
+            cues,
+            ..Default::default()
+        };
+        Ok((inner, common.sample_rate, markers))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((data.len() as u32).to_be_bytes());
+        bytes.extend(data);
+        if data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_aifc() {
+        let mut comm = vec![0, 2, 0, 0, 0, 3, 0, 16];
+        // 44.1kHz as an extended float
+        comm.extend([0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        comm.extend(b"sowt\x00\x00");
+        let mut ssnd = vec![0; 8];
+        for s in [1i16, -1, 256, -256, 1000, -1000] {
+            ssnd.extend(s.to_le_bytes());
+        }
+        let mut mark = vec![0, 2];
+        mark.extend([0, 1, 0, 0, 0, 1, 5]);
+        mark.extend(b"start");
+        mark.extend([0, 2, 0, 0, 0, 3, 0, 0]);
+        let mut inst = vec![60, 0, 0, 127, 0, 127, 0, 0];
+        inst.extend([0, 1, 0, 1, 0, 2, 0, 0, 0, 0, 0, 0]);
+
+        let mut form = b"AIFC".to_vec();
+        for (id, data) in [
+            (b"COMM", comm),
+            (b"MARK", mark),
+            (b"INST", inst),
+            (b"SSND", ssnd),
+        ] {
+            form.extend(chunk(id, &data));
+        }
+        let bytes = chunk(b"FORM", &form);
+
+        let (inner, sample_rate, markers) = Inner::parse_aiff(&bytes).unwrap();
+        assert_eq!(sample_rate, 44_100);
+        let Inner::Stereo(samples) = inner else {
+            panic!("expected stereo audio");
+        };
+        let frames: Vec<_> = samples
+            .iter()
+            .map(|s| match s {
+                Sample::I16(frame) => *frame,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(frames, [[1, -1], [256, -256], [1000, -1000]]);
+        assert_eq!(markers.loops, [(1, 3)]);
+        assert_eq!(
+            markers
+                .cues
+                .iter()
+                .map(|cue| cue.label.as_deref())
+                .collect::<Vec<_>>(),
+            [Some("start"), None]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_common() {
+        let aiff = |channels: u16, frames: u32, depth: u16| {
+            let mut comm = channels.to_be_bytes().to_vec();
+            comm.extend(frames.to_be_bytes());
+            comm.extend(depth.to_be_bytes());
+            comm.extend([0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+            let mut form = b"AIFF".to_vec();
+            form.extend(chunk(b"COMM", &comm));
+            form.extend(chunk(b"SSND", &[0; 12]));
+            Inner::parse_aiff(&chunk(b"FORM", &form)).map(|_| ())
+        };
+        assert!(aiff(1, 2, 16).is_ok());
+        assert!(matches!(
+            aiff(1, 2, 0),
+            Err(WavError::UnsupportedBitDepth(0))
+        ));
+        assert!(matches!(
+            aiff(1, 2, 33),
+            Err(WavError::UnsupportedBitDepth(33))
+        ));
+        assert!(matches!(
+            aiff(u16::MAX, u32::MAX, 32),
+            Err(WavError::MalformedChunk("SSND"))
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+//! Decoded files shared by every load of the same file, so that reloading a script doesn't decode
+//! its samples again
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use crate::{Inner, OfWav, WavError};
+
+/// A file's canonical path, and the name of the loader that decoded it
+type Key = (PathBuf, &'static str);
+/// A file's modification time and size, which change whenever it's written over
+type Stamp = (Option<SystemTime>, u64);
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache::new(Cache::CAPACITY));
+
+/// Files decoded by each loader, keyed by canonical path
+///
+/// A file is decoded again once its modification time or size changes. The least recently loaded
+/// files are evicted once the cache holds more than its capacity in samples, though any
+/// instrument still using them keeps them alive.
+pub struct Cache {
+    entries: BTreeMap<Key, Entry>,
+    /// Bytes of samples the cache holds at most
+    capacity: usize,
+    size: usize,
+    /// Incremented on every load, to order entries by when they were last loaded
+    clock: u64,
+}
+
+struct Entry {
+    wav: OfWav,
+    stamp: Stamp,
+    size: usize,
+    used: u64,
+}
+
+/// Bytes taken up by the samples of a decoded file
+fn size(inner: &Inner) -> usize {
+    match inner {
+        Inner::Mono(samples) => std::mem::size_of_val(samples.as_slice()),
+        Inner::Stereo(samples) => std::mem::size_of_val(samples.as_slice()),
+    }
+}
+
+impl Cache {
+    /// Default capacity of the shared cache, 1GiB
+    pub const CAPACITY: usize = 1 << 30;
+
+    pub const fn new(capacity: usize) -> Self {
+        Cache {
+            entries: BTreeMap::new(),
+            capacity,
+            size: 0,
+            clock: 0,
+        }
+    }
+
+    /// The cache shared by every load through [`OfWav::load`] and its counterparts
+    pub fn shared() -> MutexGuard<'static, Cache> {
+        Cache::lock(&CACHE)
+    }
+
+    fn lock(cache: &Mutex<Cache>) -> MutexGuard<'_, Cache> {
+        // A panic mid-load can't leave the cache inconsistent, as entries are inserted whole
+        cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Load the file at `path` through the shared cache, decoding it with `load` unless it's
+    /// already been decoded by the loader named `loader`
+    pub(crate) fn load(
+        path: &Path,
+        loader: &'static str,
+        load: impl FnOnce(&Path) -> Result<OfWav, WavError>,
+    ) -> Result<OfWav, WavError> {
+        Cache::load_through(&CACHE, path, loader, load)
+    }
+
+    /// [`Cache::load`] through `cache` rather than the shared one
+    fn load_through(
+        cache: &Mutex<Cache>,
+        path: &Path,
+        loader: &'static str,
+        load: impl FnOnce(&Path) -> Result<OfWav, WavError>,
+    ) -> Result<OfWav, WavError> {
+        let Some((key, stamp)) = Cache::key(path, loader) else {
+            // Let the loader report why the file can't be read
+            return load(path);
+        };
+        if let Some(wav) = Cache::lock(cache).get(&key, stamp) {
+            return Ok(wav);
+        }
+        // The cache isn't held while decoding, so other files can be loaded meanwhile
+        let wav = load(path)?;
+        Cache::lock(cache).insert(key, stamp, wav.clone());
+        Ok(wav)
+    }
+
+    fn key(path: &Path, loader: &'static str) -> Option<(Key, Stamp)> {
+        let path = fs::canonicalize(path).ok()?;
+        let metadata = fs::metadata(&path).ok()?;
+        Some(((path, loader), (metadata.modified().ok(), metadata.len())))
+    }
+
+    fn get(&mut self, key: &Key, stamp: Stamp) -> Option<OfWav> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        if entry.stamp != stamp {
+            return None;
+        }
+        entry.used = self.clock;
+        Some(entry.wav.clone())
+    }
+
+    fn insert(&mut self, key: Key, stamp: Stamp, wav: OfWav) {
+        self.clock += 1;
+        let size = size(&wav.inner);
+        let entry = Entry {
+            wav,
+            stamp,
+            size,
+            used: self.clock,
+        };
+        if let Some(previous) = self.entries.insert(key, entry) {
+            self.size -= previous.size;
+        }
+        self.size += size;
+        self.shrink();
+    }
+
+    /// Evict the least recently loaded files until the cache is within its capacity
+    fn shrink(&mut self) {
+        while self.size > self.capacity {
+            let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.size -= self.entries.remove(&key).unwrap().size;
+        }
+    }
+
+    /// Evict every decoding of the file at `path`, returning whether there were any
+    pub fn evict(&mut self, path: impl AsRef<Path>) -> bool {
+        let path = fs::canonicalize(&path).unwrap_or_else(|_| path.as_ref().to_path_buf());
+        let before = self.entries.len();
+        self.entries.retain(|(entry, _), Entry { size, .. }| {
+            let keep = *entry != path;
+            if !keep {
+                self.size -= *size;
+            }
+            keep
+        });
+        self.entries.len() < before
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    /// Set the most bytes of samples the cache holds, evicting files as needed
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes of samples the cache holds
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of decoded files the cache holds
+    pub fn files(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, sync::Arc};
+
+    use types::Sample;
+
+    use super::*;
+    use crate::tests::wav_bytes;
+
+    #[test]
+    fn reuses_until_changed() {
+        let dir = std::env::temp_dir().join(format!("of_wav-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (kick, snare) = (dir.join("kick.wav"), dir.join("snare.wav"));
+        fs::write(&kick, wav_bytes(&[1; 100], 1_000)).unwrap();
+        fs::write(&snare, wav_bytes(&[2; 50], 1_000)).unwrap();
+        let frame = std::mem::size_of::<Sample<1>>();
+
+        let cache = Mutex::new(Cache::new(Cache::CAPACITY));
+        let decodes = Cell::new(0);
+        let load = |path: &Path| {
+            Cache::load_through(&cache, path, "wav", |path| {
+                decodes.set(decodes.get() + 1);
+                OfWav::parse(&fs::read(path).map_err(hound::Error::IoError)?, "".into())
+            })
+        };
+        let first = load(&kick).unwrap();
+        let again = load(&dir.join(".").join("kick.wav")).unwrap();
+        assert!(Arc::ptr_eq(&first.inner, &again.inner));
+        assert_eq!(decodes.get(), 1);
+
+        fs::write(&kick, wav_bytes(&[3; 120], 1_000)).unwrap();
+        let changed = load(&kick).unwrap();
+        assert!(!Arc::ptr_eq(&first.inner, &changed.inner));
+        assert_eq!(changed.len(), 120);
+        let size = |cache: &Mutex<Cache>| {
+            let cache = Cache::lock(cache);
+            (cache.files(), cache.size())
+        };
+        assert_eq!(size(&cache), (1, 120 * frame));
+
+        load(&snare).unwrap();
+        assert_eq!(size(&cache), (2, 170 * frame));
+        // Kick was loaded least recently
+        Cache::lock(&cache).set_capacity(150 * frame);
+        assert_eq!(size(&cache), (1, 50 * frame));
+        load(&snare).unwrap();
+        assert_eq!(decodes.get(), 3);
+        load(&kick).unwrap();
+        assert_eq!(decodes.get(), 4);
+        // Loading kick again overflowed the cache, evicting snare
+        assert_eq!(size(&cache), (1, 120 * frame));
+        assert!(Cache::lock(&cache).evict(&kick));
+        assert!(!Cache::lock(&cache).evict(&snare));
+        assert_eq!(size(&cache), (0, 0));
+
+        // Files which can't be read are left to their loader to report, and aren't cached
+        assert!(load(&dir.join("missing.wav")).is_err());
+        assert_eq!(size(&cache), (0, 0));
+
+        // Loads through the shared cache share their samples just the same
+        let shared = OfWav::load(&snare).unwrap();
+        assert!(Arc::ptr_eq(
+            &shared.inner,
+            &OfWav::load(&snare).unwrap().inner
+        ));
+        assert!(Cache::shared().evict(&snare));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
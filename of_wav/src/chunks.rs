@@ -0,0 +1,273 @@
+//! Loop points, markers, tempo and key from the RIFF chunks `hound` skips over: `smpl`, `cue `,
+//! `LIST adtl`, `acid`, `bext` and `LIST INFO`
+
+use std::collections::HashMap;
+
+use crate::WavError;
+
+/// A marker in a wav file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub id: u32,
+    /// Frame the marker is at
+    pub position: u32,
+    pub label: Option<String>,
+    /// Frames the marker spans, if it marks a region rather than a point
+    pub length: Option<u32>,
+}
+
+/// Everything besides samples that's read from a wav file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Markers {
+    /// Start (inclusive) and end (exclusive) of each sustain loop
+    pub loops: Vec<(u32, u32)>,
+    /// Markers, ordered by position
+    pub cues: Vec<Cue>,
+    /// Tempo in beats per minute of a loop
+    pub tempo: Option<f32>,
+    /// Number of beats a loop spans
+    pub beats: Option<u32>,
+    /// Midi note of a loop's root key
+    pub root: Option<u8>,
+    /// Musical key as written in the file, e.g. `"Am"`
+    pub key: Option<String>,
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Big-endian counterpart of [`u32_at`], as used by AIFF files
+pub(crate) fn u32_be_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Text up to its null terminator
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// `tempo`, unless it's one that can't be played at
+fn playable(tempo: f32) -> Option<f32> {
+    Some(tempo).filter(|tempo| tempo.is_finite() && *tempo > 0.)
+}
+
+/// Tempo written in text as e.g. `"120 BPM"` or `"tempo=120"`
+fn tempo_in(text: &str) -> Option<f32> {
+    let text = text.to_lowercase();
+    let number = |s: &str| {
+        let s = s.trim_start_matches([' ', '=', ':']);
+        let end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        s[..end].parse().ok().and_then(playable)
+    };
+    let before_bpm = |(i, _)| {
+        let s = text[..i].trim_end();
+        let start = s
+            .rfind(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or(0, |i| i + 1);
+        number(&s[start..])
+    };
+    let after_tempo = |(i, _)| number(&text[i + "tempo".len()..]);
+    text.match_indices("bpm")
+        .find_map(before_bpm)
+        .or_else(|| text.match_indices("tempo").find_map(after_tempo))
+}
+
+/// Key written in text as e.g. `"key=Am"`, or as the whole text
+fn key_in(text: &str) -> Option<String> {
+    let text = text.trim();
+    // Lowercased as ASCII only, so that its offsets are those of `text`
+    let key = match text.to_ascii_lowercase().find("key") {
+        Some(i) => text[i + "key".len()..].trim_start_matches([' ', '=', ':']),
+        None => text,
+    };
+    let key = key.split([' ', ',', ';', '\0']).next()?;
+    key.starts_with([
+        'A', 'B', 'C', 'D', 'E', 'F', 'G', 'a', 'b', 'c', 'd', 'e', 'f', 'g',
+    ])
+    .then(|| key.to_string())
+    .filter(|key| key.len() <= 5)
+}
+
+/// Each chunk of a RIFF (or with `big_endian` sizes, IFF) list as its id and contents, stopping
+/// at the first truncated one
+pub(crate) fn chunks(mut bytes: &[u8], big_endian: bool) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let id: [u8; 4] = bytes.get(..4)?.try_into().unwrap();
+        let size = match big_endian {
+            true => u32_be_at(bytes, 4)?,
+            false => u32_at(bytes, 4)?,
+        } as usize;
+        let data = bytes.get(8..8 + size)?;
+        // Chunks are padded to an even size
+        bytes = bytes.get(8 + size + size % 2..).unwrap_or_default();
+        Some((id, data))
+    })
+}
+
+impl Markers {
+    /// Read the markers of a whole wav file, ignoring any chunks besides those holding them
+    ///
+    /// Malformed chunks are skipped as well, as the file plays just as well without their markers.
+    pub fn parse(bytes: &[u8]) -> Result<Self, WavError> {
+        let riff = match (bytes.get(..4), bytes.get(8..12)) {
+            (Some(b"RIFF"), Some(b"WAVE")) => &bytes[12..],
+            _ => return Err(WavError::MalformedChunk("RIFF")),
+        };
+
+        let mut markers = Markers::default();
+        let mut labels = HashMap::new();
+        let mut lengths = HashMap::new();
+        for (id, data) in chunks(riff, false) {
+            match &id {
+                b"smpl" => {
+                    if let Ok(loops) = Markers::sample_loops(data) {
+                        markers.loops = loops;
+                    }
+                }
+                b"cue " => {
+                    if let Ok(cues) = Markers::cue_points(data) {
+                        markers.cues = cues;
+                    }
+                }
+                b"acid" => _ = markers.acid(data),
+                b"bext" => {
+                    let description = text(data.get(..256).unwrap_or(data));
+                    markers.tempo = markers.tempo.or_else(|| tempo_in(&description));
+                    if description.to_lowercase().contains("key") {
+                        markers.key = markers.key.take().or_else(|| key_in(&description));
+                    }
+                }
+                b"LIST" if data.starts_with(b"INFO") => {
+                    for (id, data) in chunks(&data[4..], false) {
+                        match &id {
+                            b"IBPM" | b"ITMP" => {
+                                markers.tempo = markers
+                                    .tempo
+                                    .or_else(|| text(data).trim().parse().ok().and_then(playable));
+                            }
+                            b"IKEY" => {
+                                markers.key = markers.key.take().or_else(|| key_in(&text(data)))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                b"LIST" if data.starts_with(b"adtl") => {
+                    for (id, data) in chunks(&data[4..], false) {
+                        let Some(cue) = u32_at(data, 0) else {
+                            continue;
+                        };
+                        match &id {
+                            b"labl" => _ = labels.insert(cue, text(&data[4..])),
+                            b"ltxt" => _ = lengths.insert(cue, u32_at(data, 4)),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for cue in &mut markers.cues {
+            cue.label = labels.remove(&cue.id);
+            cue.length = lengths.remove(&cue.id).flatten();
+        }
+        Ok(markers)
+    }
+
+    /// Tempo, length and root key of an ACID loop, taking precedence over those from any other
+    /// chunk
+    fn acid(&mut self, data: &[u8]) -> Result<(), WavError> {
+        const ONE_SHOT: u32 = 0x01;
+        const ROOT_NOTE: u32 = 0x02;
+
+        let malformed = || WavError::MalformedChunk("acid");
+        let flags = u32_at(data, 0).ok_or_else(malformed)?;
+        let root = data.get(4).ok_or_else(malformed)?;
+        let beats = u32_at(data, 12).ok_or_else(malformed)?;
+        let tempo = f32::from_bits(u32_at(data, 20).ok_or_else(malformed)?);
+        if flags & ROOT_NOTE != 0 {
+            self.root = Some(*root);
+        }
+        if flags & ONE_SHOT == 0 {
+            self.beats = Some(beats).filter(|beats| *beats > 0);
+            self.tempo = playable(tempo).or(self.tempo);
+        }
+        Ok(())
+    }
+
+    fn sample_loops(data: &[u8]) -> Result<Vec<(u32, u32)>, WavError> {
+        const HEADER: usize = 36;
+        const LOOP: usize = 24;
+
+        let malformed = || WavError::MalformedChunk("smpl");
+        let n = u32_at(data, 28).ok_or_else(malformed)? as usize;
+        (0..n)
+            .map(|i| {
+                let offset = HEADER + i * LOOP;
+                let start = u32_at(data, offset + 8).ok_or_else(malformed)?;
+                // Loop ends are inclusive
+                let end = u32_at(data, offset + 12).ok_or_else(malformed)?;
+                Ok((start, end.saturating_add(1)))
+            })
+            .collect()
+    }
+
+    fn cue_points(data: &[u8]) -> Result<Vec<Cue>, WavError> {
+        const CUE: usize = 24;
+
+        let malformed = || WavError::MalformedChunk("cue ");
+        let n = u32_at(data, 0).ok_or_else(malformed)? as usize;
+        let mut cues = (0..n)
+            .map(|i| {
+                let offset = 4 + i * CUE;
+                Ok(Cue {
+                    id: u32_at(data, offset).ok_or_else(malformed)?,
+                    position: u32_at(data, offset + 20).ok_or_else(malformed)?,
+                    label: None,
+                    length: None,
+                })
+            })
+            .collect::<Result<Vec<_>, WavError>>()?;
+        cues.sort_by_key(|cue| cue.position);
+        Ok(cues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tempo_and_key_in_text() {
+        assert_eq!(tempo_in("Funky drums 96.5 BPM"), Some(96.5));
+        assert_eq!(tempo_in("tempo=128, key=F#m"), Some(128.));
+        assert_eq!(tempo_in("no tempo here"), None);
+        assert_eq!(key_in("tempo=128, key=F#m"), Some("F#m".into()));
+        assert_eq!(key_in("Eb"), Some("Eb".into()));
+        assert_eq!(key_in("12"), None);
+        // Characters whose lowercase is of another length don't throw the key's offset
+        assert_eq!(key_in("İkeyé"), None);
+        assert_eq!(key_in("İ key=Am"), Some("Am".into()));
+        assert_eq!(tempo_in(&format!("{} BPM", "9".repeat(40))), None);
+    }
+
+    #[test]
+    fn infinite_acid_tempo() {
+        let mut acid = [0; 24];
+        acid[20..].copy_from_slice(&f32::INFINITY.to_le_bytes());
+        let mut markers = Markers {
+            tempo: Some(90.),
+            ..Default::default()
+        };
+        markers.acid(&acid).unwrap();
+        assert_eq!(markers.tempo, Some(90.));
+    }
+}
@@ -0,0 +1,49 @@
+//! Decoded samples kept in the shared [`DiskCache`], so that files are only decoded once however
+//! often Plunder is restarted
+
+use std::{fs, path::Path};
+
+use disk_cache::{DiskCache, Samples};
+
+use crate::{Inner, WavError};
+
+impl From<Samples> for Inner {
+    fn from(samples: Samples) -> Self {
+        match samples {
+            Samples::Mono(samples) => Inner::Mono(samples),
+            Samples::Stereo(samples) => Inner::Stereo(samples),
+        }
+    }
+}
+
+impl From<Inner> for Samples {
+    fn from(inner: Inner) -> Self {
+        match inner {
+            Inner::Mono(samples) => Samples::Mono(samples),
+            Inner::Stereo(samples) => Samples::Stereo(samples),
+        }
+    }
+}
+
+/// Decode the file at `path` with `decode`, named by `transform` (see [`DiskCache::key`]),
+/// unless the shared cache already holds its samples
+///
+/// The cache failing to be written doesn't fail the load, which only takes longer next time.
+pub(crate) fn load(
+    path: &Path,
+    transform: &str,
+    decode: impl FnOnce(Vec<u8>) -> Result<(Inner, u32), WavError>,
+) -> Result<(Inner, u32), WavError> {
+    let source = fs::read(path).map_err(hound::Error::IoError)?;
+    let Some(cache) = DiskCache::shared() else {
+        return decode(source);
+    };
+    let key = DiskCache::key(&source, transform.as_bytes());
+    if let Some((samples, sample_rate)) = cache.get(&key) {
+        return Ok((samples.into(), sample_rate));
+    }
+    let (inner, sample_rate) = decode(source)?;
+    let samples = Samples::from(inner);
+    _ = cache.put(&key, &samples, sample_rate);
+    Ok((samples.into(), sample_rate))
+}
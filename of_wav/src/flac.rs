@@ -0,0 +1,123 @@
+//! FLAC files, decoded into the same samples as wav files
+
+use std::io;
+
+use types::Sample;
+
+use crate::{Inner, WavError};
+
+/// A sample of any bit-depth, as the bytes of a 24-bit sample
+fn to_i24(sample: i32, depth: u32) -> [u8; 3] {
+    let sample = if depth <= 24 {
+        sample << (24 - depth)
+    } else {
+        sample >> (depth - 24)
+    };
+    sample.to_be_bytes()[1..].try_into().unwrap()
+}
+
+impl Inner {
+    /// Decode a FLAC stream, along with its sample rate
+    ///
+    /// 16-bit audio is kept as is, and every other depth is widened or narrowed to 24 bits.
+    pub fn read_flac<R: io::Read>(
+        mut reader: claxon::FlacReader<R>,
+    ) -> Result<(Self, u32), WavError> {
+        let info = reader.streaminfo();
+        let depth = info.bits_per_sample;
+        let samples = reader
+            .samples()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(WavError::Flac)?;
+        let sample = |s: i32| match depth {
+            16 => Sample::I16([s as i16]),
+            _ => Sample::I24([to_i24(s, depth)]),
+        };
+        let inner = match info.channels {
+            1 => Inner::Mono(samples.into_iter().map(sample).collect()),
+            2 => Inner::Stereo(
+                samples
+                    .chunks_exact(2)
+                    .map(|frame| match (sample(frame[0]), sample(frame[1])) {
+                        (Sample::I16([l]), Sample::I16([r])) => Sample::I16([l, r]),
+                        (Sample::I24([l]), Sample::I24([r])) => Sample::I24([l, r]),
+                        _ => unreachable!(),
+                    })
+                    .collect(),
+            ),
+            n => return Err(WavError::UnsupportedNumChannels(n as u16)),
+        };
+        Ok((inner, info.sample_rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CRC of `bytes` with polynomial `poly` of `WIDTH` bits, as FLAC checks its frames with
+    fn crc<const WIDTH: u32>(bytes: &[u8], poly: u16) -> u16 {
+        let mask = ((1u32 << WIDTH) - 1) as u16;
+        let mut crc = 0u16;
+        for byte in bytes {
+            crc ^= (*byte as u16) << (WIDTH - 8);
+            for _ in 0..8 {
+                let top = crc & (1 << (WIDTH - 1)) != 0;
+                crc = (crc << 1) & mask;
+                if top {
+                    crc ^= poly;
+                }
+            }
+        }
+        crc
+    }
+
+    /// A FLAC stream of one frame of verbatim 16-bit `channels`, each 16 samples long
+    fn flac(channels: &[[i16; 16]]) -> Vec<u8> {
+        let mut bytes = b"fLaC".to_vec();
+        // The only metadata block, a 34-byte STREAMINFO
+        bytes.extend([0x80, 0, 0, 34]);
+        bytes.extend([0, 16, 0, 16, 0, 0, 0, 0, 0, 0]);
+        let n = channels.len() as u64;
+        let packed = 8_000 << 44 | (n - 1) << 41 | 15 << 36 | 16;
+        bytes.extend(packed.to_be_bytes());
+        bytes.extend([0; 16]);
+
+        // Fixed block size of 16 given at the end of the header, independent channels, 16 bits
+        let mut frame = vec![0xff, 0xf8, 0x60, ((n as u8 - 1) << 4) | 0b1000, 0, 15];
+        frame.push(crc::<8>(&frame, 0x07) as u8);
+        for channel in channels {
+            // Verbatim subframe
+            frame.push(0b10);
+            frame.extend(channel.iter().flat_map(|s| s.to_be_bytes()));
+        }
+        frame.extend(crc::<16>(&frame, 0x8005).to_be_bytes());
+        bytes.extend(frame);
+        bytes
+    }
+
+    #[test]
+    fn decodes_flac() {
+        let left: [i16; 16] = std::array::from_fn(|i| i as i16 * 100 - 800);
+        let right = left.map(|s| -s);
+        let reader = claxon::FlacReader::new(io::Cursor::new(flac(&[left, right]))).unwrap();
+        let (inner, sample_rate) = Inner::read_flac(reader).unwrap();
+        assert_eq!(sample_rate, 8_000);
+        let Inner::Stereo(samples) = inner else {
+            panic!("expected stereo audio");
+        };
+        let decoded: Vec<_> = samples
+            .iter()
+            .map(|s| match s {
+                Sample::I16(frame) => *frame,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            decoded,
+            (0..16).map(|i| [left[i], right[i]]).collect::<Vec<_>>()
+        );
+
+        assert_eq!(to_i24(-1, 20), [0xff, 0xff, 0xf0]);
+    }
+}
@@ -1,8 +1,22 @@
-//! `ofWav`, a wav-file loading plugin for Plunder
+//! `ofWav`, an audio-file loading plugin for Plunder, reading wav, FLAC, Ogg Vorbis, MP3 and AIFF files
 
-use std::{fmt, path::Path, sync::Arc};
+mod aiff;
+mod cache;
+mod chunks;
+mod disk;
+mod flac;
+mod lossy;
+mod transients;
+pub use cache::Cache;
+pub use chunks::{Cue, Markers};
+pub use transients::{SliceMode, SlicesConfig, onsets};
 
-use types::{LuaUserData, Sample};
+use std::{fmt, fs, io, ops::Range, path::Path, sync::Arc};
+
+use types::{
+    BiInstrument, FromLua, FromLuaMulti, Lua, LuaResult, LuaUserData, LuaUserDataFields,
+    LuaUserDataMethods, LuaValue, Sample, amplitude,
+};
 
 pub enum Inner {
     Mono(Vec<Sample<1>>),
@@ -12,16 +26,24 @@ pub enum Inner {
 #[derive(Debug)]
 pub enum WavError {
     Hound(hound::Error),
+    Flac(claxon::Error),
+    Decode(symphonia::core::errors::Error),
     UnsupportedBitDepth(u16),
     UnsupportedNumChannels(u16),
+    MalformedChunk(&'static str),
+    UnsupportedCompression(String),
 }
 
 impl fmt::Display for WavError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             WavError::Hound(error) => error.fmt(f),
+            WavError::Flac(error) => error.fmt(f),
+            WavError::Decode(error) => error.fmt(f),
             WavError::UnsupportedBitDepth(n) => write!(f, "Unsupported bit-depth {n}"),
             WavError::UnsupportedNumChannels(n) => write!(f, "Unsupported number of channels {n}"),
+            WavError::MalformedChunk(id) => write!(f, "Malformed \"{id}\" chunk"),
+            WavError::UnsupportedCompression(kind) => write!(f, "Unsupported compression {kind}"),
         }
     }
 }
@@ -34,11 +56,20 @@ impl From<hound::Error> for WavError {
     }
 }
 
+impl From<symphonia::core::errors::Error> for WavError {
+    fn from(value: symphonia::core::errors::Error) -> Self {
+        WavError::Decode(value)
+    }
+}
+
 impl Inner {
     pub fn load(path: impl AsRef<Path>) -> Result<Self, WavError> {
+        Inner::read(hound::WavReader::open(path)?)
+    }
+
+    pub fn read<R: io::Read>(mut reader: hound::WavReader<R>) -> Result<Self, WavError> {
         use itertools::Itertools as _;
 
-        let mut reader = hound::WavReader::open(path)?;
         let depth = reader.spec().bits_per_sample;
         let channels = reader.spec().channels;
         Ok(match channels {
@@ -98,43 +129,838 @@ impl Inner {
     }
 }
 
+/// A wav file, or a region of one
+///
+/// Regions are views sharing the file's samples, so neither slicing nor editing ever copies them:
+/// edits are applied to frames as they're read.
 #[derive(Clone)]
-pub struct OfWav(Arc<Inner>);
+pub struct OfWav {
+    inner: Arc<Inner>,
+    /// Frames of `inner` in view
+    range: Range<usize>,
+    /// Whether the region plays from the end of `range` back to its start
+    reversed: bool,
+    /// Gains applied to frames as they're read
+    gains: Arc<[Gain]>,
+    /// Loops and markers of the whole file, positioned from its start
+    markers: Arc<Markers>,
+    sample_rate: u32,
+    name: String,
+}
+
+/// Decoder of FLAC files, keying the samples it decodes on disk along with its version, as pinned
+/// in `Cargo.toml`, so that files are decoded again once it's upgraded
+const FLAC_DECODER: &str = "claxon 0.4.3";
+/// Decoder of Ogg Vorbis and MP3 files, see [`FLAC_DECODER`]
+const LOSSY_DECODER: &str = "symphonia 0.5.5";
+
+/// Name of the file at `path`
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.display().to_string(),
+    )
+}
 
 impl OfWav {
+    /// Load a wav file, or a FLAC, Ogg Vorbis, MP3 or AIFF file by its extension
+    ///
+    /// Files are only decoded again once they've changed, see [`Cache`], as are those loaded by
+    /// every other loader.
     pub fn load(path: impl AsRef<Path>) -> Result<Self, WavError> {
-        Inner::load(path).map(|inner| OfWav(Arc::new(inner)))
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("flac") => return OfWav::load_flac(path),
+            Some("ogg" | "oga" | "mp3") => return OfWav::load_lossy(path),
+            Some("aif" | "aiff" | "aifc") => return OfWav::load_aiff(path),
+            _ => {}
+        }
+        Cache::load(path, "wav", |path| {
+            OfWav::parse(
+                &fs::read(path).map_err(hound::Error::IoError)?,
+                file_name(path),
+            )
+        })
+    }
+
+    pub fn load_flac(path: impl AsRef<Path>) -> Result<Self, WavError> {
+        Cache::load(path.as_ref(), "flac", |path| {
+            let (inner, sample_rate) = disk::load(path, FLAC_DECODER, |bytes| {
+                let reader =
+                    claxon::FlacReader::new(io::Cursor::new(bytes)).map_err(WavError::Flac)?;
+                Inner::read_flac(reader)
+            })?;
+            Ok(OfWav::new(inner, sample_rate, file_name(path)))
+        })
+    }
+
+    /// Load an Ogg Vorbis or MP3 file, telling them apart by their contents
+    pub fn load_lossy(path: impl AsRef<Path>) -> Result<Self, WavError> {
+        Cache::load(path.as_ref(), "lossy", |path| {
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            let (inner, sample_rate) = disk::load(path, LOSSY_DECODER, |bytes| {
+                Inner::read_lossy(Box::new(io::Cursor::new(bytes)), extension)
+            })?;
+            Ok(OfWav::new(inner, sample_rate, file_name(path)))
+        })
+    }
+
+    pub fn load_aiff(path: impl AsRef<Path>) -> Result<Self, WavError> {
+        Cache::load(path.as_ref(), "aiff", |path| {
+            OfWav::parse_aiff(
+                &fs::read(path).map_err(hound::Error::IoError)?,
+                file_name(path),
+            )
+        })
+    }
+
+    /// Read a whole AIFF or AIFC file, along with its loops and markers
+    pub fn parse_aiff(bytes: &[u8], name: String) -> Result<Self, WavError> {
+        let (inner, sample_rate, markers) = Inner::parse_aiff(bytes)?;
+        Ok(OfWav {
+            markers: Arc::new(markers),
+            ..OfWav::new(inner, sample_rate, name)
+        })
+    }
+
+    /// Read a whole wav file, along with its loops and markers
+    pub fn parse(bytes: &[u8], name: String) -> Result<Self, WavError> {
+        let markers = Markers::parse(bytes)?;
+        let wav = OfWav::read(hound::WavReader::new(bytes)?, name)?;
+        Ok(OfWav {
+            markers: Arc::new(markers),
+            ..wav
+        })
+    }
+
+    pub fn read<R: io::Read>(reader: hound::WavReader<R>, name: String) -> Result<Self, WavError> {
+        let sample_rate = reader.spec().sample_rate;
+        Ok(OfWav::new(Inner::read(reader)?, sample_rate, name))
+    }
+
+    fn new(inner: Inner, sample_rate: u32, name: String) -> Self {
+        let len = match &inner {
+            Inner::Mono(samples) => samples.len(),
+            Inner::Stereo(samples) => samples.len(),
+        };
+        OfWav {
+            inner: Arc::new(inner),
+            range: 0..len,
+            reversed: false,
+            gains: Arc::new([]),
+            markers: Arc::default(),
+            sample_rate,
+            name,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Region of frames `start..end` of this one, clamped to its length
+    pub fn slice(&self, start: usize, end: usize) -> OfWav {
+        let end = end.min(self.len());
+        let start = start.min(end);
+        let Range {
+            start: first,
+            end: last,
+        } = self.range;
+        OfWav {
+            range: match self.reversed {
+                true => last - end..last - start,
+                false => first + start..first + end,
+            },
+            name: format!("{}[{start}..{end}]", self.name),
+            ..self.clone()
+        }
+    }
+
+    /// Cut into `n` slices of equal length, one after another
+    pub fn chop(&self, n: usize) -> Vec<OfWav> {
+        let len = self.len();
+        (0..n)
+            .map(|i| self.slice(i * len / n, (i + 1) * len / n))
+            .collect()
+    }
+
+    /// Sustain loops lying within the region, positioned from its start
+    pub fn loops(&self) -> Vec<(u32, u32)> {
+        let Range { start, end } = self.range;
+        self.markers
+            .loops
+            .iter()
+            .filter(|(s, e)| *s as usize >= start && *e as usize <= end)
+            .map(|(s, e)| match self.reversed {
+                true => (end as u32 - e, end as u32 - s),
+                false => (s - start as u32, e - start as u32),
+            })
+            .collect()
+    }
+
+    /// Markers lying within the region, positioned from its start
+    pub fn cues(&self) -> Vec<Cue> {
+        let Range { start, end } = self.range;
+        let (start, end) = (start as u32, end as u32);
+        let mut cues: Vec<_> = self
+            .markers
+            .cues
+            .iter()
+            .filter(|cue| self.range.contains(&(cue.position as usize)))
+            .map(|cue| Cue {
+                // Reversed, a marker spanning frames begins where its span ends
+                position: match self.reversed {
+                    true => end - cue.position - cue.length.unwrap_or(0).min(end - cue.position),
+                    false => cue.position - start,
+                },
+                ..cue.clone()
+            })
+            .collect();
+        if self.reversed {
+            cues.reverse();
+        }
+        cues
+    }
+
+    /// Cut at each onset found according to `config`, see [`onsets`]
+    pub fn slices(&self, config: &SlicesConfig) -> Vec<OfWav> {
+        use itertools::Itertools as _;
+        use types::Instrument as _;
+
+        let mut samples = vec![Sample::F32([0.]); self.len()];
+        self.fill(0, &mut samples);
+        let samples: Vec<f32> = samples.iter().map(|s| s.to_f32()[0]).collect();
+
+        let starts = match config.mode {
+            SliceMode::Transients => onsets(&samples, self.sample_rate, config),
+            SliceMode::Markers => std::iter::once(0)
+                .chain(self.cues().iter().map(|cue| cue.position as usize))
+                .dedup()
+                .collect(),
+        };
+        let ends = starts.iter().skip(1).copied().chain([self.len()]);
+        starts
+            .iter()
+            .zip(ends)
+            .map(|(start, end)| self.slice(*start, end))
+            .collect()
+    }
+
+    /// The region played backwards
+    pub fn reverse(&self) -> OfWav {
+        OfWav {
+            reversed: !self.reversed,
+            name: format!("{}:reverse()", self.name),
+            ..self.clone()
+        }
+    }
+
+    /// The region scaled by `db` decibels
+    pub fn gain(&self, db: f32) -> OfWav {
+        self.scaled(format!("gain({db})"), Gain::Constant(amplitude(db)))
+    }
+
+    /// The region scaled so that its peak is at `db` decibels
+    pub fn normalize(&self, db: f32) -> OfWav {
+        let peak = self.levels().into_iter().fold(0., f32::max);
+        let gain = if peak > 0. { amplitude(db) / peak } else { 1. };
+        self.scaled(format!("normalize({db})"), Gain::Constant(gain))
+    }
+
+    /// The region faded in linearly over its first `frames` frames
+    pub fn fade_in(&self, frames: usize) -> OfWav {
+        self.scaled(format!("fadeIn({frames})"), self.ramp(0, true, frames))
+    }
+
+    /// The region faded out linearly over its last `frames` frames
+    pub fn fade_out(&self, frames: usize) -> OfWav {
+        self.scaled(
+            format!("fadeOut({frames})"),
+            self.ramp(self.len(), false, frames),
+        )
+    }
+
+    /// Region between the first and last frames louder than `db` decibels
+    pub fn trim_silence(&self, db: f32) -> OfWav {
+        let threshold = amplitude(db);
+        let levels = self.levels();
+        let loud = |level: &f32| *level > threshold;
+        match (levels.iter().position(loud), levels.iter().rposition(loud)) {
+            (Some(start), Some(end)) => self.slice(start, end + 1),
+            _ => self.slice(0, 0),
+        }
+    }
+
+    /// Level of the loudest channel of each frame in the region
+    fn levels(&self) -> Vec<f32> {
+        let ids = 0..self.len();
+        match &*self.inner {
+            Inner::Mono(samples) => ids
+                .map(|id| self.edited_frame(samples, id).to_f32()[0].abs())
+                .collect(),
+            Inner::Stereo(samples) => ids
+                .map(|id| {
+                    let frame = self.edited_frame(samples, id).to_f32();
+                    frame.into_iter().fold(0., |peak, s| s.abs().max(peak))
+                })
+                .collect(),
+        }
+    }
+
+    /// The region with `gain` applied on top of its other edits
+    fn scaled(&self, edit: String, gain: Gain) -> OfWav {
+        OfWav {
+            gains: self.gains.iter().copied().chain([gain]).collect(),
+            name: format!("{}:{edit}", self.name),
+            ..self.clone()
+        }
+    }
+
+    /// Gain rising from silence at `zero` frames into the region to full level `frames` frames
+    /// later, or earlier unless `rising`
+    fn ramp(&self, zero: usize, rising: bool, frames: usize) -> Gain {
+        // Frames into the region are `a * frame + b` of frames of the file, where `a` is 1 or -1
+        let (a, b) = match self.reversed {
+            true => (-1, self.range.end as isize - 1),
+            false => (1, -(self.range.start as isize)),
+        };
+        Gain::Ramp {
+            edge: (zero as isize - b) * a,
+            direction: if rising { a } else { -a },
+            frames,
+        }
+    }
+
+    /// Frame `id` of the region, read through its edits
+    fn edited_frame<const CHANNELS: usize>(
+        &self,
+        samples: &[Sample<CHANNELS>],
+        id: usize,
+    ) -> Sample<CHANNELS> {
+        let frame = match self.reversed {
+            true => self.range.end - 1 - id,
+            false => self.range.start + id,
+        };
+        let sample = samples[frame];
+        if self.gains.is_empty() {
+            return sample;
+        }
+        let gain: f32 = self.gains.iter().map(|gain| gain.at(frame)).product();
+        Sample::F32(sample.to_f32().map(|s| s * gain))
+    }
+
+    /// Fill `frames` from frame `start` of the region, converting each one with `f`
+    fn fill_with<const IN: usize, const OUT: usize>(
+        &self,
+        samples: &[Sample<IN>],
+        start: u32,
+        frames: &mut [Sample<OUT>],
+        f: impl Fn(Sample<IN>) -> Sample<OUT>,
+    ) -> usize {
+        if !self.reversed && self.gains.is_empty() {
+            return types::fill_from_slice(&samples[self.range.clone()], start, frames, f);
+        }
+        let n = frames.len().min(self.len().saturating_sub(start as usize));
+        for (i, frame) in frames[..n].iter_mut().enumerate() {
+            *frame = f(self.edited_frame(samples, start as usize + i));
+        }
+        n
+    }
+
+    /// Frame nearest to `position` into the region
+    fn frame(&self, position: Position) -> usize {
+        match position {
+            Position::Frames(frames) => frames,
+            Position::Seconds(seconds) => (seconds * self.sample_rate as f64).round() as usize,
+        }
+    }
+}
+
+/// A gain applied to a frame by its position in the file
+#[derive(Debug, Clone, Copy)]
+enum Gain {
+    Constant(f32),
+    /// Rising linearly from silence at frame `edge` to full level `frames` frames on, counting
+    /// forwards if `direction` is 1 or backwards if it's -1
+    Ramp {
+        edge: isize,
+        direction: isize,
+        frames: usize,
+    },
+}
+
+impl Gain {
+    fn at(&self, frame: usize) -> f32 {
+        match *self {
+            Gain::Constant(gain) => gain,
+            Gain::Ramp {
+                edge,
+                direction,
+                frames,
+            } => {
+                let distance = (frame as isize - edge) * direction;
+                (distance as f32 / frames.max(1) as f32).clamp(0., 1.)
+            }
+        }
+    }
+}
+
+/// A position into an instrument from Lua: numbers count frames, while `{seconds = ..}` counts
+/// seconds (as does `{frames = ..}` frames)
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Frames(usize),
+    Seconds(f64),
+}
+
+impl FromLua for Position {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
+        let LuaValue::Table(table) = value else {
+            return Ok(Position::Frames(usize::from_lua(value, lua)?));
+        };
+        match (
+            table.get::<Option<usize>>("frames")?,
+            table.get::<Option<f64>>("seconds")?,
+        ) {
+            (Some(frames), None) => Ok(Position::Frames(frames)),
+            (None, Some(seconds)) => Ok(Position::Seconds(seconds.max(0.))),
+            _ => Err(types::LuaError::RuntimeError(
+                "expected a number of frames, `{frames = ..}` or `{seconds = ..}`".into(),
+            )),
+        }
     }
 }
 
 impl types::Instrument<1> for OfWav {
     fn ok(&self) -> Result<(), String> {
-        // Of_wav will provide an implementation to interpolate stereo audio to mono
+        // Stereo audio is downmixed to mono
         Ok(())
     }
 
     fn get(&self, id: u32) -> Option<types::Sample<1>> {
-        match &*self.0 {
-            Inner::Mono(samples) => samples.get(id as usize).copied(),
-            Inner::Stereo(_) => unimplemented!("Interpolation of stereo audio to mono"),
+        let id = id as usize;
+        (id < self.len()).then(|| match &*self.inner {
+            Inner::Mono(samples) => self.edited_frame(samples, id),
+            Inner::Stereo(samples) => self.edited_frame(samples, id).to_mono(),
+        })
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        match &*self.inner {
+            Inner::Mono(samples) => self.fill_with(samples, start, frames, |s| s),
+            Inner::Stereo(samples) => self.fill_with(samples, start, frames, Sample::to_mono),
         }
     }
 }
 
 impl types::Instrument<2> for OfWav {
     fn ok(&self) -> Result<(), String> {
-        // Of_wav will provide an implementation to interpolate mono audio to stereo
+        // Mono audio is upmixed to stereo
         Ok(())
     }
 
     fn get(&self, id: u32) -> Option<types::Sample<2>> {
-        match &*self.0 {
-            Inner::Mono(_) => unimplemented!("Interpolation of mono audio to stereo"),
-            Inner::Stereo(samples) => samples.get(id as usize).copied(),
+        let id = id as usize;
+        (id < self.len()).then(|| match &*self.inner {
+            Inner::Mono(samples) => self.edited_frame(samples, id).to_stereo(),
+            Inner::Stereo(samples) => self.edited_frame(samples, id),
+        })
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        match &*self.inner {
+            Inner::Mono(samples) => self.fill_with(samples, start, frames, Sample::to_stereo),
+            Inner::Stereo(samples) => self.fill_with(samples, start, frames, |s| s),
+        }
+    }
+}
+
+impl LuaUserData for OfWav {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("loops", |lua, this| {
+            lua.create_sequence_from(
+                this.loops()
+                    .into_iter()
+                    .map(|(start, end)| lua.create_sequence_from([start, end]))
+                    .collect::<LuaResult<Vec<_>>>()?,
+            )
+        });
+        fields.add_field_method_get("beats", |_, this| Ok(this.markers.beats));
+        fields.add_field_method_get("rootNote", |_, this| Ok(this.markers.root));
+        fields.add_field_method_get("key", |_, this| Ok(this.markers.key.clone()));
+        fields.add_field_method_get("cues", |lua, this| {
+            lua.create_sequence_from(
+                this.cues()
+                    .into_iter()
+                    .map(|cue| {
+                        let table = lua.create_table()?;
+                        table.set("id", cue.id)?;
+                        table.set("position", cue.position)?;
+                        table.set("label", cue.label)?;
+                        table.set("length", cue.length)?;
+                        Ok(table)
+                    })
+                    .collect::<LuaResult<Vec<_>>>()?,
+            )
+        });
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method(
+            "slice",
+            |_, this, (start, end): (Position, Option<Position>)| {
+                let end = end.map_or(this.len(), |end| this.frame(end));
+                Ok(Box::new(this.slice(this.frame(start), end)) as Box<dyn BiInstrument>)
+            },
+        );
+        methods.add_method("chop", |_, this, n: usize| {
+            Ok(this
+                .chop(n)
+                .into_iter()
+                .map(|slice| Box::new(slice) as Box<dyn BiInstrument>)
+                .collect::<Vec<_>>())
+        });
+        methods.add_method("slices", |_, this, config: Option<SlicesConfig>| {
+            Ok(this
+                .slices(&config.unwrap_or_default())
+                .into_iter()
+                .map(|slice| Box::new(slice) as Box<dyn BiInstrument>)
+                .collect::<Vec<_>>())
+        });
+
+        fn edit<M: LuaUserDataMethods<OfWav>, A: FromLuaMulti + 'static>(
+            methods: &mut M,
+            name: &str,
+            f: fn(&OfWav, A) -> OfWav,
+        ) {
+            methods.add_method(name, move |_, this, args: A| {
+                Ok(Box::new(f(this, args)) as Box<dyn BiInstrument>)
+            });
         }
+        edit(methods, "reverse", |this, ()| this.reverse());
+        edit(methods, "gain", |this, db: f32| this.gain(db));
+        edit(methods, "normalize", |this, db: Option<f32>| {
+            this.normalize(db.unwrap_or(0.))
+        });
+        edit(methods, "fadeIn", |this, time: Position| {
+            this.fade_in(this.frame(time))
+        });
+        edit(methods, "fadeOut", |this, time: Position| {
+            this.fade_out(this.frame(time))
+        });
+        edit(methods, "trimSilence", |this, db: Option<f32>| {
+            this.trim_silence(db.unwrap_or(-60.))
+        });
+    }
+}
+
+impl types::InstrumentFactory for OfWav {
+    type Args = String;
+    type Instrument = OfWav;
+    const NAME: &str = "ofWav";
+
+    fn construct(path: String) -> types::LuaResult<Option<OfWav>> {
+        OfWav::load(path)
+            .map(Some)
+            .map_err(|err| types::LuaError::ExternalError(Arc::new(err)))
+    }
+}
+
+/// Loads FLAC files as [`OfWav`]s, whatever their extension
+pub struct OfFlac;
+
+impl types::InstrumentFactory for OfFlac {
+    type Args = String;
+    type Instrument = OfWav;
+    const NAME: &str = "ofFlac";
+
+    fn construct(path: String) -> types::LuaResult<Option<OfWav>> {
+        OfWav::load_flac(path)
+            .map(Some)
+            .map_err(|err| types::LuaError::ExternalError(Arc::new(err)))
+    }
+}
+
+/// Loads Ogg Vorbis and MP3 files as [`OfWav`]s, whatever their extension
+pub struct OfLossy;
+
+impl types::InstrumentFactory for OfLossy {
+    type Args = String;
+    type Instrument = OfWav;
+    const NAME: &str = "ofLossy";
+
+    fn construct(path: String) -> types::LuaResult<Option<OfWav>> {
+        OfWav::load_lossy(path)
+            .map(Some)
+            .map_err(|err| types::LuaError::ExternalError(Arc::new(err)))
     }
 }
 
-impl LuaUserData for OfWav {}
+/// Loads AIFF and AIFC files as [`OfWav`]s, whatever their extension
+pub struct OfAiff;
 
-impl types::BiInstrument for OfWav {}
+impl types::InstrumentFactory for OfAiff {
+    type Args = String;
+    type Instrument = OfWav;
+    const NAME: &str = "ofAiff";
+
+    fn construct(path: String) -> types::LuaResult<Option<OfWav>> {
+        OfWav::load_aiff(path)
+            .map(Some)
+            .map_err(|err| types::LuaError::ExternalError(Arc::new(err)))
+    }
+}
+
+impl types::BiInstrument for OfWav {
+    fn loop_points(&self) -> Option<(u32, u32)> {
+        self.loops().first().copied()
+    }
+
+    fn length(&self) -> Option<u32> {
+        Some(OfWav::len(self) as u32)
+    }
+
+    fn channels(&self) -> u16 {
+        match &*self.inner {
+            Inner::Mono(_) => 1,
+            Inner::Stereo(_) => 2,
+        }
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn tempo(&self) -> Option<f32> {
+        self.markers.tempo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use types::Instrument;
+
+    use super::*;
+
+    /// A mono 16-bit wav file of `samples`
+    pub(crate) fn wav_bytes(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for sample in samples {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    fn wav(samples: &[i16], sample_rate: u32) -> OfWav {
+        OfWav::parse(&wav_bytes(samples, sample_rate), "test.wav".into()).unwrap()
+    }
+
+    /// Append a chunk to a RIFF file, updating its size
+    fn push_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], data: &[u32]) {
+        bytes.extend(id);
+        bytes.extend((data.len() as u32 * 4).to_le_bytes());
+        bytes.extend(data.iter().flat_map(|n| n.to_le_bytes()));
+        let size = bytes.len() as u32 - 8;
+        bytes[4..8].copy_from_slice(&size.to_le_bytes());
+    }
+
+    fn samples(wav: &OfWav) -> Vec<i16> {
+        (0..)
+            .map_while(|id| Instrument::<1>::get(wav, id))
+            .map(|s| match s {
+                Sample::I16([s]) => s,
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slice_and_chop() {
+        let wav = wav(&(0..10).collect::<Vec<_>>(), 1_000);
+        let slice = wav.slice(2, 8);
+        assert_eq!(samples(&slice), [2, 3, 4, 5, 6, 7]);
+        assert_eq!(samples(&slice.slice(1, 100)), [3, 4, 5, 6, 7]);
+        assert!(Arc::ptr_eq(&wav.inner, &slice.inner));
+        assert_eq!(slice.name(), "test.wav[2..8]");
+
+        let kit = wav.chop(3);
+        assert_eq!(
+            kit.iter().map(samples).collect::<Vec<_>>(),
+            [vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8, 9]]
+        );
+        let mut block = [Sample::I16([0]); 4];
+        assert_eq!(Instrument::<1>::fill(&kit[1], 1, &mut block), 2);
+    }
+
+    #[test]
+    fn transient_slices() {
+        // Decaying 500Hz hits at 8kHz, the third much quieter than the rest
+        let hits = [(0, 0.5), (2_000, 0.5), (4_100, 0.01), (6_050, 0.5)];
+        let mut samples = vec![0; 8_000];
+        for (start, level) in hits {
+            for i in 0..1_500 {
+                let t = i as f32 / 8_000.;
+                let s = level * (-t / 0.02).exp() * (std::f32::consts::TAU * 500. * t).sin();
+                samples[start + i] = (s * 32_767.) as i16;
+            }
+        }
+        let wav = wav(&samples, 8_000);
+
+        let starts = |sensitivity| {
+            let slices = wav.slices(&SlicesConfig {
+                sensitivity,
+                ..Default::default()
+            });
+            assert_eq!(slices.iter().map(OfWav::len).sum::<usize>(), wav.len());
+            slices
+                .iter()
+                .map(|slice| slice.range.start)
+                .collect::<Vec<_>>()
+        };
+        // Onsets are found to within a 40-frame window
+        assert_eq!(starts(0.5), [0, 2_000, 6_040]);
+        assert_eq!(starts(1.), [0, 2_000, 4_080, 6_040]);
+        assert_eq!(starts(1.), starts(1.));
+    }
+
+    #[test]
+    fn markers() {
+        let mut bytes = wav_bytes(&(0..100).collect::<Vec<_>>(), 1_000);
+        // One forward loop over frames 20 to 79 inclusive
+        let mut smpl = vec![0; 7];
+        smpl.extend([1, 0]);
+        smpl.extend([0, 0, 20, 79, 0, 0]);
+        push_chunk(&mut bytes, b"smpl", &smpl);
+        // Cues listed out of order, the second of them labelled "b"
+        push_chunk(
+            &mut bytes,
+            b"cue ",
+            &[2, 1, 0, 0x6174_6164, 0, 0, 60, 2, 0, 0x6174_6164, 0, 0, 30],
+        );
+        let labl = u32::from_le_bytes(*b"labl");
+        let b = u32::from_le_bytes(*b"b\0\0\0");
+        push_chunk(
+            &mut bytes,
+            b"LIST",
+            &[u32::from_le_bytes(*b"adtl"), labl, 8, 2, b],
+        );
+        // Malformed chunks, too short for what they declare, are skipped
+        push_chunk(&mut bytes, b"smpl", &[0; 7]);
+        push_chunk(&mut bytes, b"cue ", &[2, 3]);
+        push_chunk(&mut bytes, b"acid", &[1]);
+        push_chunk(
+            &mut bytes,
+            b"LIST",
+            &[u32::from_le_bytes(*b"adtl"), labl, 0],
+        );
+
+        let wav = OfWav::parse(&bytes, "test.wav".into()).unwrap();
+        assert_eq!(samples(&wav).len(), 100);
+        assert_eq!(wav.loop_points(), Some((20, 80)));
+        let cue = |id, position, label: Option<&str>| Cue {
+            id,
+            position,
+            label: label.map(Into::into),
+            length: None,
+        };
+        assert_eq!(wav.cues(), [cue(2, 30, Some("b")), cue(1, 60, None)]);
+
+        // Views only see the markers within them
+        let slice = wav.slice(10, 50);
+        assert_eq!(slice.loop_points(), None);
+        assert_eq!(slice.cues(), [cue(2, 20, Some("b"))]);
+        let reversed = wav.reverse();
+        assert_eq!(reversed.loop_points(), Some((20, 80)));
+        assert_eq!(reversed.cues(), [cue(1, 40, None), cue(2, 70, Some("b"))]);
+        assert_eq!(reversed.slice(50, 100).cues(), [cue(2, 20, Some("b"))]);
+        let slices = wav.slices(&SlicesConfig {
+            mode: SliceMode::Markers,
+            ..Default::default()
+        });
+        assert_eq!(
+            slices.iter().map(|s| s.range.clone()).collect::<Vec<_>>(),
+            [0..30, 30..60, 60..100]
+        );
+    }
+
+    fn levels(wav: &OfWav) -> Vec<f32> {
+        (0..)
+            .map_while(|id| Instrument::<1>::get(wav, id))
+            .map(|s| (s.to_f32()[0] * 32_768.).round())
+            .collect()
+    }
+
+    #[test]
+    fn edits() {
+        let wav = wav(&[0, 0, 100, -200, 400, 0, 0, 0], 1_000);
+        let trimmed = wav.trim_silence(-60.);
+        assert_eq!(samples(&trimmed), [100, -200, 400]);
+        assert_eq!(samples(&trimmed.reverse()), [400, -200, 100]);
+        assert_eq!(levels(&trimmed.gain(6.0206)), [200., -400., 800.]);
+        assert_eq!(
+            levels(&trimmed.normalize(-6.0206)),
+            [4_096., -8_192., 16_384.]
+        );
+        assert_eq!(levels(&trimmed.fade_in(2)), [0., -100., 400.]);
+        assert_eq!(levels(&trimmed.fade_out(2)), [100., -200., 200.]);
+        // Edits are applied on top of each other, and to slices of the edited region
+        let reversed = trimmed.reverse();
+        assert_eq!(samples(&reversed.slice(0, 2)), [400, -200]);
+        assert_eq!(levels(&reversed.fade_in(2)), [0., -100., 100.]);
+        assert_eq!(levels(&reversed.fade_out(2).slice(1, 3)), [-200., 50.]);
+        assert_eq!(
+            levels(&reversed.gain(6.0206).reverse()),
+            [200., -400., 800.]
+        );
+        // Edits share the file's samples, leaving the original be
+        assert!(Arc::ptr_eq(&wav.inner, &reversed.gain(6.).inner));
+        assert_eq!(samples(&wav).len(), 8);
+        assert_eq!(trimmed.reverse().name(), "test.wav[2..5]:reverse()");
+    }
+
+    #[test]
+    fn tempo_and_key() {
+        let mut bytes = wav_bytes(&[0; 100], 1_000);
+        let word = |bytes: &[u8; 4]| u32::from_le_bytes(*bytes);
+        push_chunk(
+            &mut bytes,
+            b"LIST",
+            &[word(b"INFO"), word(b"IKEY"), 4, word(b"Am\0\0")],
+        );
+        // A looping ACID file of 8 beats in 4/4 at 120BPM, rooted at A3
+        push_chunk(
+            &mut bytes,
+            b"acid",
+            &[0x02, 57, 0, 8, 4 | 4 << 16, 120f32.to_bits()],
+        );
+
+        let wav = OfWav::parse(&bytes, "loop.wav".into()).unwrap();
+        assert_eq!(wav.tempo(), Some(120.));
+        assert_eq!(wav.markers.beats, Some(8));
+        assert_eq!(wav.markers.root, Some(57));
+        assert_eq!(wav.markers.key.as_deref(), Some("Am"));
+        assert_eq!(wav.slice(10, 20).reverse().tempo(), Some(120.));
+    }
+}
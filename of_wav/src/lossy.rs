@@ -0,0 +1,160 @@
+//! Ogg Vorbis and MP3 files, decoded into the same samples as wav files
+
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::DecoderOptions,
+    errors::Error,
+    formats::FormatOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+use types::Sample;
+
+use crate::{Inner, WavError};
+
+impl Inner {
+    /// Decode an Ogg Vorbis or MP3 stream, along with its sample rate
+    ///
+    /// The encoder's delay and padding are trimmed off, so that loops stay sample-accurate.
+    pub fn read_lossy(
+        source: Box<dyn MediaSource>,
+        extension: Option<&str>,
+    ) -> Result<(Self, u32), WavError> {
+        let mut hint = Hint::new();
+        if let Some(extension) = extension {
+            hint.with_extension(extension);
+        }
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                MediaSourceStream::new(source, Default::default()),
+                &options,
+                &MetadataOptions::default(),
+            )?
+            .format;
+        let track = format
+            .default_track()
+            .ok_or(Error::Unsupported("no track"))?;
+        let (track_id, params) = (track.id, track.codec_params.clone());
+        let mut decoder =
+            symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+
+        let mut samples = Vec::new();
+        // Channels and sample rate of the last packet decoded
+        let mut spec = None;
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(error)) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(error) => return Err(error.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            let decoded = match decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt packet is skipped over, as a player would
+                Err(Error::DecodeError(_)) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            spec = Some(*decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+
+        let spec = spec.ok_or(Error::DecodeError("no audio packets in the stream"))?;
+        let inner = match spec.channels.count() {
+            1 => Inner::Mono(samples.into_iter().map(|s| Sample::F32([s])).collect()),
+            2 => Inner::Stereo(
+                samples
+                    .chunks_exact(2)
+                    .map(|frame| Sample::F32([frame[0], frame[1]]))
+                    .collect(),
+            ),
+            n => return Err(WavError::UnsupportedNumChannels(n as u16)),
+        };
+        Ok((inner, spec.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// An MP3 stream of `frames` silent MPEG frames of 1152 samples each, mono at 44.1kHz, led by
+    /// a LAME tag declaring the encoder's `delay` and `padding`
+    fn mp3_bytes(frames: u32, delay: u32, padding: u32) -> Vec<u8> {
+        // MPEG-1 layer III at 128kbps, without a CRC, 417 bytes long
+        const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0xc0];
+        const LEN: usize = 417;
+        // Mono side information, all zeros for silence
+        const SIDE_INFO: usize = 17;
+
+        let mut tag = vec![0; LEN];
+        tag[..4].copy_from_slice(&HEADER);
+        let mut info = b"Info".to_vec();
+        // Only the number of frames is given, followed by the LAME extension
+        info.extend(1u32.to_be_bytes());
+        info.extend(frames.to_be_bytes());
+        // Written as by libavformat, whose tags needn't carry a CRC
+        info.extend(b"Lavf\0\0\0\0\0");
+        info.extend([0; 12]);
+        // Delay and padding as the encoder writes them, offset by the decoder's own delay of 529
+        // frames, in 12 bits each
+        let trim = (delay - 529) << 12 | (padding + 529);
+        info.extend(&trim.to_be_bytes()[1..]);
+        tag[4 + SIDE_INFO..][..info.len()].copy_from_slice(&info);
+
+        let mut frame = vec![0; LEN];
+        frame[..4].copy_from_slice(&HEADER);
+        let mut bytes = tag;
+        for _ in 0..frames {
+            bytes.extend(&frame);
+        }
+        bytes
+    }
+
+    #[test]
+    fn trims_gapless_mp3() {
+        let source = Box::new(Cursor::new(mp3_bytes(4, 1_105, 1_000)));
+        let (inner, sample_rate) = Inner::read_lossy(source, Some("mp3")).unwrap();
+        assert_eq!(sample_rate, 44_100);
+        let Inner::Mono(samples) = inner else {
+            panic!("expected mono samples");
+        };
+        // Only the frames the encoder was given are left
+        assert_eq!(samples.len(), 4 * 1_152 - 1_105 - 1_000);
+        assert!(samples.iter().all(|s| s.to_f32() == [0.]));
+    }
+
+    #[test]
+    fn rejects_streams_without_audio() {
+        // A LAME tag, missing the frame it declares
+        let mut bytes = mp3_bytes(1, 529, 0);
+        bytes.truncate(417);
+        let source = Box::new(Cursor::new(bytes));
+        assert!(matches!(
+            Inner::read_lossy(source, Some("mp3")),
+            Err(WavError::Decode(Error::DecodeError(_)))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let source = Box::new(Cursor::new(b"not audio at all".repeat(64)));
+        assert!(matches!(
+            Inner::read_lossy(source, Some("mp3")),
+            Err(WavError::Decode(_))
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+//! Onset detection, for slicing loops at each hit
+
+use types::{FromLua, Lua, LuaDeserializer, LuaResult, LuaValue};
+
+/// Quietest level in decibels told apart from silence
+const FLOOR: f32 = -60.;
+
+/// Windows back that a rise in level is measured from
+const LOOKBACK: usize = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SliceMode {
+    /// Slice at each detected onset
+    #[default]
+    Transients,
+    /// Slice at each marker of the file's `cue ` chunk
+    Markers,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SlicesConfig {
+    pub mode: SliceMode,
+    /// How readily onsets are detected, from `0.0` (only the sharpest hits) to `1.0`
+    pub sensitivity: f32,
+    /// Shortest time in seconds between two onsets
+    pub min_gap: f32,
+}
+
+impl Default for SlicesConfig {
+    fn default() -> Self {
+        SlicesConfig {
+            mode: SliceMode::default(),
+            sensitivity: 0.5,
+            min_gap: 0.05,
+        }
+    }
+}
+
+impl FromLua for SlicesConfig {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        use serde::Deserialize as _;
+        SlicesConfig::deserialize(LuaDeserializer::new(value))
+    }
+}
+
+/// Frames at which a sound starts in `samples`, always including the first
+///
+/// Audio is measured in windows of 5ms, and an onset is wherever the level of a window rises
+/// far enough above that of the windows just before it, from 48dB at a sensitivity of `0.0` down
+/// to 6dB at `1.0`.
+pub fn onsets(samples: &[f32], sample_rate: u32, config: &SlicesConfig) -> Vec<usize> {
+    let window = (sample_rate as usize / 200).max(16);
+    let threshold = 6. + (1. - config.sensitivity.clamp(0., 1.)) * 42.;
+    let min_gap = (config.min_gap.max(0.) * sample_rate as f32) as usize;
+
+    let levels: Vec<f32> = samples
+        .chunks(window)
+        .map(|chunk| {
+            let power = chunk.iter().map(|s| s * s).sum::<f32>() / chunk.len() as f32;
+            (10. * power.max(1e-12).log10()).max(FLOOR)
+        })
+        .collect();
+
+    let mut onsets = vec![0];
+    for (i, level) in levels.iter().enumerate().skip(1) {
+        let before = levels[i.saturating_sub(LOOKBACK)..i]
+            .iter()
+            .fold(f32::INFINITY, |min, level| min.min(*level));
+        let frame = i * window;
+        if level - before >= threshold && frame >= onsets[onsets.len() - 1] + min_gap {
+            onsets.push(frame);
+        }
+    }
+    onsets
+}
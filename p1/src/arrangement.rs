@@ -0,0 +1,324 @@
+//! Arrangements, sequencing sections (usually `p1` sheets) into a song
+
+use std::{f32::consts::FRAC_PI_2, str::FromStr};
+
+use types::*;
+
+use crate::{Instruments, P1, P1Buffer, P1Error, loop_id};
+
+//
+// Config
+//
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ArrangementConfig {
+    /// Length in samples of the crossfade into each section, where `0` is a hard cut
+    #[serde(default)]
+    pub crossfade: usize,
+}
+
+impl FromLua for ArrangementConfig {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        use serde::Deserialize as _;
+        ArrangementConfig::deserialize(LuaDeserializer::new(value))
+    }
+}
+
+//
+// Order
+//
+
+/// A section played some number of times in a row
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub section: String,
+    pub repeats: usize,
+    /// Crossfade into the section, in place of [`ArrangementConfig::crossfade`]
+    pub crossfade: Option<usize>,
+}
+
+/// The order that the sections of an arrangement are played in
+///
+/// Entries are separated by commas or newlines, each naming a section followed by an optional
+/// number of repeats (`xN`) and crossfade into it in samples (`~N`):
+/// ```text
+/// intro, verse x4, chorus x2 ~500
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct Order(pub Vec<Entry>);
+
+impl Order {
+    const SEPARATORS: [char; 2] = [',', '\n'];
+    const REPEATS: char = 'x';
+    const CROSSFADE: char = '~';
+}
+
+impl FromStr for Order {
+    type Err = P1Error;
+
+    fn from_str(order: &str) -> Result<Self, P1Error> {
+        order
+            .split(Self::SEPARATORS)
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let mut words = entry.split_whitespace();
+                let section = words.next().expect("entry is not empty").to_string();
+                let mut entry = Entry {
+                    section,
+                    repeats: 1,
+                    crossfade: None,
+                };
+                for word in words {
+                    let number = |prefix| word.strip_prefix(prefix)?.parse().ok();
+                    match (number(Self::REPEATS), number(Self::CROSSFADE)) {
+                        (Some(repeats), _) => entry.repeats = repeats,
+                        (_, Some(crossfade)) => entry.crossfade = Some(crossfade),
+                        _ => {
+                            return Err(P1Error::Order(format!(
+                                "unexpected \"{word}\" after section \"{}\"",
+                                entry.section
+                            )));
+                        }
+                    }
+                }
+                Ok(entry)
+            })
+            .collect::<Result<_, _>>()
+            .map(Order)
+    }
+}
+
+impl FromLua for Order {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        let string = value
+            .as_string()
+            .ok_or(LuaError::RuntimeError("expected string".into()))?
+            .to_string_lossy();
+        Ok(Order::from_str(&string)?)
+    }
+}
+
+//
+// Rendered Buffer
+//
+
+/// A single play of a section
+pub struct Segment<'a> {
+    pub section: &'a str,
+    pub instrument: &'a dyn BiInstrument,
+    /// Length in samples of the crossfade into the segment
+    pub crossfade: usize,
+}
+
+impl<'a> Segment<'a> {
+    /// Expand `order` into the segments it plays, looking up each section in `sections`
+    pub fn sequence(
+        config: &ArrangementConfig,
+        order: &'a Order,
+        sections: &'a Instruments,
+    ) -> Result<Vec<Self>, P1Error> {
+        let mut segments = Vec::new();
+        for entry in &order.0 {
+            let instrument = sections
+                .get(&entry.section)
+                .ok_or_else(|| P1Error::UnboundInstrument(entry.section.clone()))?;
+            // Repeats of a section loop seamlessly, so only its first play is crossfaded into
+            for repeat in 0..entry.repeats {
+                segments.push(Segment {
+                    section: &entry.section,
+                    instrument,
+                    crossfade: match repeat {
+                        0 => entry.crossfade.unwrap_or(config.crossfade),
+                        _ => 0,
+                    },
+                });
+            }
+        }
+        // There's nothing to fade in from before the first segment
+        if let Some(first) = segments.first_mut() {
+            first.crossfade = 0;
+        }
+        Ok(segments)
+    }
+}
+
+impl P1Buffer {
+    pub fn arrange(
+        config: &ArrangementConfig,
+        order: &Order,
+        sections: &Instruments,
+    ) -> Result<Option<Self>, P1Error> {
+        P1Buffer::sequence(&Segment::sequence(config, order, sections)?)
+    }
+
+    /// Play `segments` one after another
+    ///
+    /// The buffer is stereo unless one of the sections can only produce mono
+    pub fn sequence(segments: &[Segment]) -> Result<Option<Self>, P1Error> {
+        if segments.is_empty() {
+            return Ok(None);
+        }
+
+        if segments
+            .iter()
+            .all(|segment| Instrument::<2>::ok(segment.instrument).is_ok())
+        {
+            let buffer = sequence(segments, |instrument, id| {
+                Instrument::<2>::get(instrument, id).map(|s| s.to_f32())
+            })?;
+            return Ok(Some(P1Buffer::Stereo(
+                buffer.into_iter().map(Sample::F32).collect(),
+            )));
+        }
+
+        for segment in segments {
+            Instrument::<1>::ok(segment.instrument)
+                .map_err(|error| P1Error::InstrumentNotOk(segment.section.to_string(), error))?;
+        }
+        let buffer = sequence(segments, |instrument, id| {
+            Instrument::<1>::get(instrument, id).map(|s| s.to_f32())
+        })?;
+        Ok(Some(P1Buffer::Mono(
+            buffer.into_iter().map(Sample::F32).collect(),
+        )))
+    }
+}
+
+/// Mix `segments` end to end
+///
+/// A crossfade starts where the previous segment ends, keeping sections on the beat: the previous
+/// segment carries on past its end (around its loop, if it has one) as it fades out.
+fn sequence<const CHANNELS: usize>(
+    segments: &[Segment],
+    get: impl Fn(&dyn BiInstrument, u32) -> Option<[f32; CHANNELS]>,
+) -> Result<Vec<[f32; CHANNELS]>, P1Error> {
+    let lens: Vec<_> = segments.iter().map(section_len).collect::<Result<_, _>>()?;
+    let mut buffer = vec![[0.; CHANNELS]; lens.iter().sum()];
+
+    let mut start = 0;
+    for (i, (segment, &len)) in segments.iter().zip(&lens).enumerate() {
+        let fade_in = segment.crossfade;
+        let fade_out = segments.get(i + 1).map_or(0, |next| next.crossfade);
+        for id in 0..(len + fade_out).min(buffer.len() - start) {
+            // Equal-power fades
+            let gain = if id < fade_in {
+                (id as f32 / fade_in as f32 * FRAC_PI_2).sin()
+            } else if id >= len {
+                ((id - len) as f32 / fade_out as f32 * FRAC_PI_2).cos()
+            } else {
+                1.
+            };
+            let Some(frame) = get(segment.instrument, loop_id(segment.instrument, id)) else {
+                break;
+            };
+            for (out, s) in buffer[start + id].iter_mut().zip(frame) {
+                *out += gain * s;
+            }
+        }
+        start += len;
+    }
+    Ok(buffer)
+}
+
+/// Length of a segment's section, which has to be known up front as a section may loop forever
+fn section_len(segment: &Segment) -> Result<usize, P1Error> {
+    segment
+        .instrument
+        .length()
+        .map(|len| len as usize)
+        .ok_or_else(|| P1Error::LengthUnknown(segment.section.to_string()))
+}
+
+//
+// P1, the Instrument
+//
+impl P1 {
+    pub fn arrange(
+        config: &ArrangementConfig,
+        order: &Order,
+        sections: &Instruments,
+    ) -> Result<Option<Self>, P1Error> {
+        Ok(P1Buffer::arrange(config, order, sections)?.map(P1::from))
+    }
+}
+
+/// Factory for arranged sheets, see [`P1::arrange`]
+pub struct Arrangement;
+
+impl InstrumentFactory for Arrangement {
+    type Args = (ArrangementConfig, Order, Instruments);
+    type Instrument = P1;
+    const NAME: &str = "arrangement.render";
+
+    fn construct((config, order, sections): Self::Args) -> LuaResult<Option<P1>> {
+        Ok(P1::arrange(&config, &order, &sections)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::test_util::TestInstrument;
+
+    use super::*;
+
+    #[test]
+    fn order_from_str() {
+        assert_eq!(
+            Order::from_str("intro, verse x4\nchorus x2 ~500,").unwrap(),
+            Order(vec![
+                Entry {
+                    section: "intro".into(),
+                    repeats: 1,
+                    crossfade: None,
+                },
+                Entry {
+                    section: "verse".into(),
+                    repeats: 4,
+                    crossfade: None,
+                },
+                Entry {
+                    section: "chorus".into(),
+                    repeats: 2,
+                    crossfade: Some(500),
+                },
+            ]),
+        );
+        assert!(Order::from_str("intro 4").is_err());
+    }
+
+    #[test]
+    fn sequence_crossfade() {
+        let section = |samples: &[f32]| {
+            P1::from(P1Buffer::Mono(
+                samples.iter().map(|s| Sample::F32([*s])).collect(),
+            ))
+        };
+        let (a, b) = (section(&[1., 1.]), section(&[2., 2., 2.]));
+        let segments = [(&a, 0), (&a, 0), (&b, 2)].map(|(instrument, crossfade)| Segment {
+            section: "",
+            instrument,
+            crossfade,
+        });
+        let buffer = P1::from(P1Buffer::sequence(&segments).unwrap().unwrap());
+        assert_eq!(
+            (0..)
+                .map_while(|id| Instrument::<1>::get(&buffer, id))
+                .map(|s| (s.to_f32()[0] * 1000.).round() / 1000.)
+                .collect::<Vec<_>>(),
+            vec![1., 1., 1., 1., 1., 2.121, 2.],
+        );
+    }
+
+    #[test]
+    fn sequence_unknown_length() {
+        let segments = [Segment {
+            section: "drone",
+            instrument: &TestInstrument::drone(),
+            crossfade: 0,
+        }];
+        assert!(matches!(
+            P1Buffer::sequence(&segments),
+            Err(P1Error::LengthUnknown(section)) if section == "drone"
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+//! Groove templates, offsetting the timing and velocity of sheet columns
+
+use crate::{P1Error, Sheet};
+
+/// Timing and velocity offset of a single step in a [`Groove`]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub struct GrooveStep {
+    /// Fraction of an interval by which the step is delayed (negative values pull it early)
+    #[serde(default)]
+    pub timing: f32,
+    /// Gain applied to everything triggered on the step
+    #[serde(default = "GrooveStep::unity")]
+    pub velocity: f32,
+}
+
+impl GrooveStep {
+    fn unity() -> f32 {
+        1.
+    }
+}
+
+impl Default for GrooveStep {
+    fn default() -> Self {
+        GrooveStep {
+            timing: 0.,
+            velocity: GrooveStep::unity(),
+        }
+    }
+}
+
+/// A template of steps, repeated over the columns of a sheet
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(try_from = "GrooveSource")]
+pub struct Groove(pub Vec<GrooveStep>);
+
+/// The ways a groove can be provided from Lua
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum GrooveSource {
+    /// A sheet of `timing` and `velocity` rows, see [`Groove::from_sheet`]
+    Sheet(String),
+    /// A list of `{ timing = .., velocity = .. }` tables
+    Steps(Vec<GrooveStep>),
+}
+
+impl TryFrom<GrooveSource> for Groove {
+    type Error = P1Error;
+
+    fn try_from(source: GrooveSource) -> Result<Self, P1Error> {
+        match source {
+            GrooveSource::Sheet(sheet) => Groove::from_sheet(&sheet),
+            GrooveSource::Steps(steps) => Ok(Groove(steps)),
+        }
+    }
+}
+
+impl Groove {
+    const ROW_TIMING: &str = "timing";
+    const ROW_VELOCITY: &str = "velocity";
+
+    /// Parse a groove from a labelled sheet
+    ///
+    /// Each column of the `timing` row delays its step by tenths of an interval (`0`-`9`), and
+    /// each column of the `velocity` row scales its step by ninths (`0`-`9`). Blank columns leave
+    /// the step untouched.
+    /// ```text
+    ///          | [      ]
+    /// timing   |  3 3 3 3
+    /// velocity | 9595 959
+    /// ```
+    pub fn from_sheet(sheet: &str) -> Result<Self, P1Error> {
+        let mut lines = sheet.lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| P1Error::Groove("empty groove sheet".into()))?;
+        // Columns are counted in characters, so that labels needn't be ASCII
+        let column_of = |c: char| header.chars().position(|h| h == c);
+        let sep_column = column_of(Sheet::SEPARATOR)
+            .ok_or_else(|| P1Error::Groove("groove sheet must be labelled".into()))?;
+        let rows: Vec<_> = lines
+            .filter_map(|line| {
+                let (split, _) = line.char_indices().nth(sep_column)?;
+                let pat = &line[split..];
+                let pat = pat.strip_prefix(Sheet::SEPARATOR).unwrap_or(pat);
+                (!pat.is_empty()).then(|| (line[..split].trim(), pat))
+            })
+            .collect();
+
+        // Column of a loop marker within the rows' patterns
+        let pat_column = |column: usize, marker: char| {
+            column
+                .checked_sub(sep_column + 1)
+                .ok_or_else(|| P1Error::Groove(format!("'{marker}' must come after the separator")))
+        };
+        let loop_start = match column_of(Sheet::LOOP_START) {
+            Some(column) => pat_column(column, Sheet::LOOP_START)?,
+            None => 0,
+        };
+        let loop_end = match column_of(Sheet::LOOP_END) {
+            Some(column) => pat_column(column, Sheet::LOOP_END)?,
+            None => rows
+                .iter()
+                .map(|(_, pat)| pat.chars().count())
+                .max()
+                .unwrap_or(0)
+                .saturating_sub(1),
+        };
+
+        let mut steps = vec![GrooveStep::default(); (loop_start..=loop_end).count()];
+        for (label, pat) in rows {
+            let set: fn(&mut GrooveStep, u32) = match label {
+                Self::ROW_TIMING => |step, d| step.timing = d as f32 / 10.,
+                Self::ROW_VELOCITY => |step, d| step.velocity = d as f32 / 9.,
+                label => return Err(P1Error::Groove(format!("unknown groove row \"{label}\""))),
+            };
+            for (step, c) in steps.iter_mut().zip(pat.chars().skip(loop_start)) {
+                match c {
+                    Sheet::EMPTY => (),
+                    c => set(
+                        step,
+                        c.to_digit(10).ok_or_else(|| {
+                            P1Error::Groove(format!("unexpected '{c}' in {label} row"))
+                        })?,
+                    ),
+                }
+            }
+        }
+        Ok(Groove(steps))
+    }
+
+    /// The step applied to the given column, if the groove has any steps
+    pub fn step(&self, column: usize) -> Option<&GrooveStep> {
+        (!self.0.is_empty()).then(|| &self.0[column % self.0.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_sheet_errors() {
+        let groove = Groove::from_sheet("timing ⌚ | [ ]\ntiming ⌚ |  5\n");
+        assert!(matches!(groove, Err(P1Error::Groove(_))));
+        let groove = Groove::from_sheet("      | [ ]\ntiming|  5\n").unwrap();
+        assert_eq!(groove.0[1].timing, 0.5);
+
+        for sheet in ["[ ] |\ntiming | 5", "] |\ntiming | 5"] {
+            assert!(matches!(Groove::from_sheet(sheet), Err(P1Error::Groove(_))));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Lazy sheets, computing samples as they're played rather than rendering up front
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
+
+use types::*;
+
+use crate::{
+    Config, Frames, InstrumentValue, Instruments, P1Buffer, P1Error, Reborrow, Row, Run, Sheet,
+    read_run,
+};
+
+/// A row reduced to what's needed to compute any one of its samples
+pub struct LazyRow {
+    pub name: String,
+    pub interval: usize,
+    pub stretch: f64,
+    pub cycle: usize,
+    pub runs: Vec<Run>,
+}
+
+impl LazyRow {
+    pub fn new(config: &Config, row: &Row) -> Self {
+        LazyRow {
+            name: row.name.clone(),
+            interval: row.interval,
+            stretch: row.stretch,
+            cycle: row.cycle(),
+            runs: row.runs(config),
+        }
+    }
+
+    /// Add the row's frames from `start` onwards onto `frames`, reading its instrument through
+    /// `fill`
+    ///
+    /// Produces the same frames as the row's buffer when mixed up front, see
+    /// [`crate::RowBuffer`].
+    pub fn mix_block<const CHANNELS: usize>(
+        &self,
+        instrument: &dyn BiInstrument,
+        start: usize,
+        frames: &mut [[f32; CHANNELS]],
+        fill: impl Fn(u32, &mut [Sample<CHANNELS>]) -> usize,
+    ) {
+        if self.cycle == 0 {
+            return;
+        }
+        for run in &self.runs {
+            let run_start = (run.column * self.interval) as isize + run.offset;
+            let run_start = run_start.rem_euclid(self.cycle as isize) as usize;
+            let len = run.len * self.interval;
+            let mut j = 0;
+            while j < frames.len() {
+                // Position within the run, which may have wrapped around the end of the row
+                let i = ((start + j) % self.cycle + self.cycle - run_start) % self.cycle;
+                if i >= len {
+                    j += self.cycle - i;
+                    continue;
+                }
+                let n = (len - i).min(frames.len() - j);
+                let out = &mut frames[j..j + n];
+                let source = run.source * self.interval + i;
+                read_run(instrument, self.stretch, source, n, &fill, |k, frame| {
+                    for (out, s) in out[k].iter_mut().zip(frame) {
+                        *out += run.velocity * s;
+                    }
+                });
+                j += n;
+            }
+        }
+    }
+}
+
+/// An instrument of a lazy sheet, only borrowed while a block is computed so that it stays usable
+/// from Lua in the meantime
+enum Handle {
+    UserData(Reborrow),
+    Owned(Box<dyn BiInstrument>),
+}
+
+impl From<InstrumentValue> for Handle {
+    fn from(instrument: InstrumentValue) -> Self {
+        match instrument {
+            // Releases the borrow, keeping the value it was borrowed from
+            InstrumentValue::UserData(_, reborrow, _) => Handle::UserData(reborrow),
+            InstrumentValue::Owned(instrument) => Handle::Owned(instrument),
+        }
+    }
+}
+
+/// A sheet whose samples are computed a block at a time as they're played
+///
+/// Only the most recently played blocks are kept, so a lazy sheet never holds more than
+/// [`LazyBuffer::CACHED_BLOCKS`] blocks of samples however long it is.
+pub struct LazyBuffer {
+    rows: Vec<(LazyRow, Handle)>,
+    size: usize,
+    stereo: bool,
+    blocks: RefCell<VecDeque<(usize, Rc<Frames>)>>,
+    /// Why an instrument couldn't be borrowed for a block, which was computed without it
+    error: RefCell<Option<String>>,
+}
+
+impl LazyBuffer {
+    pub const BLOCK: usize = 4096;
+    pub const CACHED_BLOCKS: usize = 16;
+
+    pub fn new(
+        config: &Config,
+        sheet: &Sheet,
+        instruments: Instruments,
+    ) -> Result<Option<Self>, P1Error> {
+        let (rows, size, stereo) = {
+            let rows = Row::route(config, sheet, &instruments)?;
+            if rows.is_empty() {
+                return Ok(None);
+            }
+            let size = P1Buffer::size(config, sheet.columns(), &rows);
+            let stereo = P1Buffer::stereo(&rows)?;
+            let rows: Vec<_> = rows.iter().map(|row| LazyRow::new(config, row)).collect();
+            (rows, size, stereo)
+        };
+        let mut handles: HashMap<_, _> = instruments.into_named().into_iter().collect();
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let handle = handles
+                    .remove(&row.name)
+                    .expect("rows are only created for routed instruments");
+                (row, Handle::from(handle))
+            })
+            .collect();
+        Ok(Some(LazyBuffer {
+            rows,
+            size,
+            stereo,
+            blocks: RefCell::new(VecDeque::with_capacity(Self::CACHED_BLOCKS)),
+            error: RefCell::new(None),
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn stereo(&self) -> bool {
+        self.stereo
+    }
+
+    /// The block containing `id`, from the cache if it was played recently
+    fn block(&self, id: usize) -> Rc<Frames> {
+        let block = id / Self::BLOCK;
+        let cached = self
+            .blocks
+            .borrow()
+            .iter()
+            .find(|(b, _)| *b == block)
+            .cloned();
+        if let Some((_, frames)) = cached {
+            return frames;
+        }
+
+        let frames = Rc::new(self.render_block(block * Self::BLOCK));
+        let mut blocks = self.blocks.borrow_mut();
+        if blocks.len() == Self::CACHED_BLOCKS {
+            blocks.pop_front();
+        }
+        blocks.push_back((block, frames.clone()));
+        frames
+    }
+
+    fn render_block(&self, start: usize) -> Frames {
+        fn mix<const CHANNELS: usize>(
+            buffer: &LazyBuffer,
+            start: usize,
+            fill: impl Fn(&dyn BiInstrument, u32, &mut [Sample<CHANNELS>]) -> usize,
+        ) -> Vec<[f32; CHANNELS]> {
+            let mut frames = vec![[0.; CHANNELS]; LazyBuffer::BLOCK.min(buffer.size - start)];
+            for (row, handle) in &buffer.rows {
+                let borrowed;
+                let instrument: &dyn BiInstrument = match handle {
+                    Handle::UserData(reborrow) => match reborrow() {
+                        Ok(instrument) => {
+                            borrowed = instrument;
+                            &**borrowed
+                        }
+                        Err(error) => {
+                            buffer.error.borrow_mut().get_or_insert_with(|| {
+                                format!("instrument of row \"{}\" unavailable: {error}", row.name)
+                            });
+                            continue;
+                        }
+                    },
+                    Handle::Owned(instrument) => &**instrument,
+                };
+                row.mix_block(instrument, start, &mut frames, |id, block| {
+                    fill(instrument, id, block)
+                });
+            }
+            frames
+        }
+
+        match self.stereo {
+            true => Frames::Stereo(mix(self, start, Instrument::<2>::fill)),
+            false => Frames::Mono(mix(self, start, Instrument::<1>::fill)),
+        }
+    }
+}
+
+impl LazyBuffer {
+    /// Fill `frames` a block at a time, converting each frame of a block with `f`
+    fn fill_with<const CHANNELS: usize>(
+        &self,
+        start: u32,
+        frames: &mut [Sample<CHANNELS>],
+        f: impl Fn(&Frames, usize) -> Sample<CHANNELS>,
+    ) -> usize {
+        let start = start as usize;
+        let mut filled = 0;
+        while filled < frames.len() && start + filled < self.size {
+            let id = start + filled;
+            let block = self.block(id);
+            let offset = id % Self::BLOCK;
+            let n = (frames.len() - filled)
+                .min(Self::BLOCK - offset)
+                .min(self.size - id);
+            for (i, frame) in frames[filled..filled + n].iter_mut().enumerate() {
+                *frame = f(&block, offset + i);
+            }
+            filled += n;
+        }
+        filled
+    }
+}
+
+impl Instrument<1> for LazyBuffer {
+    fn ok(&self) -> Result<(), String> {
+        self.error.borrow().clone().map_or(Ok(()), Err)
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        let id = id as usize;
+        if id >= self.size {
+            return None;
+        }
+        Some(match &*self.block(id) {
+            Frames::Mono(frames) => Sample::F32(frames[id % Self::BLOCK]),
+            Frames::Stereo(frames) => Sample::F32(frames[id % Self::BLOCK]).to_mono(),
+        })
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        self.fill_with(start, frames, |block, i| match block {
+            Frames::Mono(frames) => Sample::F32(frames[i]),
+            Frames::Stereo(frames) => Sample::F32(frames[i]).to_mono(),
+        })
+    }
+}
+
+impl Instrument<2> for LazyBuffer {
+    fn ok(&self) -> Result<(), String> {
+        Instrument::<1>::ok(self)
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        let id = id as usize;
+        if id >= self.size {
+            return None;
+        }
+        Some(match &*self.block(id) {
+            Frames::Mono(frames) => Sample::F32(frames[id % Self::BLOCK]).to_stereo(),
+            Frames::Stereo(frames) => Sample::F32(frames[id % Self::BLOCK]),
+        })
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        self.fill_with(start, frames, |block, i| match block {
+            Frames::Mono(frames) => Sample::F32(frames[i]).to_stereo(),
+            Frames::Stereo(frames) => Sample::F32(frames[i]),
+        })
+    }
+}
@@ -1,23 +1,45 @@
 //! `p1`, the flagship parser instrument included with Plunder
 
-use std::{collections::HashMap, fmt, ops::Deref as _, str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
 use types::*;
 
+mod arrangement;
+mod groove;
+mod lazy;
+mod lua_instrument;
+pub use arrangement::{Arrangement, ArrangementConfig, Entry, Order, Segment};
+pub use groove::{Groove, GrooveStep};
+pub use lazy::{LazyBuffer, LazyRow};
+pub use lua_instrument::LuaInstrument;
+
 #[derive(Debug)]
 pub enum P1Error {
     Lua(LuaError),
-    Sheet,
+    Sheet(String),
     InstrumentUnknown(String),
     ArrangementMismatch(bool),
     UnboundInstrument(String),
+    InstrumentNotOk(String, String),
+    Groove(String),
+    Order(String),
+    LengthUnknown(String),
 }
 
 impl fmt::Display for P1Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             P1Error::Lua(error) => error.fmt(f),
-            P1Error::Sheet => write!(f, "Sheet error"),
+            P1Error::Sheet(error) => write!(f, "Sheet error: {error}"),
             P1Error::InstrumentUnknown(name) => write!(
                 f,
                 "Instrument provided for \"{name}\" is of an unrecognized Lua type"
@@ -34,6 +56,15 @@ impl fmt::Display for P1Error {
                 f,
                 "Sheet mentions instrument \"{name}\" which is not provided in instruments"
             ),
+            P1Error::InstrumentNotOk(name, error) => {
+                write!(f, "Instrument \"{name}\" can't be rendered: {error}")
+            }
+            P1Error::Groove(error) => write!(f, "Groove error: {error}"),
+            P1Error::Order(error) => write!(f, "Arrangement order error: {error}"),
+            P1Error::LengthUnknown(name) => write!(
+                f,
+                "Section \"{name}\" can't be arranged as its length is unknown"
+            ),
         }
     }
 }
@@ -46,6 +77,8 @@ impl From<LuaError> for P1Error {
     }
 }
 
+// `LuaError` is only `Send` and `Sync` with mlua's `send` feature
+#[allow(clippy::arc_with_non_send_sync)]
 impl From<P1Error> for LuaError {
     fn from(value: P1Error) -> Self {
         match value {
@@ -62,7 +95,12 @@ pub type SourceIndexList = Vec<Option<usize>>;
 
 /// The kinds of sheets that the `p1` plugin can take
 ///
-/// All entries are guaranteed to be strings of the same length
+/// All entries span the loop, except in labelled sheets where a row's label declares its own
+/// length in columns (e.g. `perc/5 |`), letting it loop independently of the others
+///
+/// The suffix is a length rather than a resolution: a row's columns are as wide as every other
+/// row's unless its [`RowConfig::interval`] says otherwise, e.g. triplet hats take an interval of
+/// `4/3` of the sheet's. A label whose suffix isn't a number keeps its `/` (e.g. `hi/hat`).
 #[derive(Debug)]
 pub enum Sheet {
     Labelled {
@@ -82,6 +120,7 @@ impl Sheet {
     const LOOP_START: char = '[';
     const LOOP_END: char = ']';
     const EMPTY: char = ' ';
+    const LENGTH: char = '/';
 
     const PAT_ONE_SHOT: char = 'o';
     const PAT_RESTART: char = '[';
@@ -90,46 +129,52 @@ impl Sheet {
     const PAT_UNPAUSE: char = '(';
     const PAT_PAUSE: char = ')';
 
-    fn pat_to_source_index_list<S: AsRef<str>>(s: S) -> SourceIndexList {
+    fn pat_to_source_index_list<S: AsRef<str>>(s: S) -> Result<SourceIndexList, P1Error> {
         let mut on = false;
         let mut iota = 0;
         s.as_ref()
             .chars()
-            .map(|c| match c {
-                Self::PAT_ONE_SHOT | Self::PAT_RESTART => {
-                    on = true;
-                    iota = 1;
-                    Some(0)
-                }
-                Self::PAT_SUSTAIN => on.then(|| {
-                    iota += 1;
-                    iota - 1
-                }),
-                Self::PAT_HALT => on.then(|| {
-                    on = false;
-                    let n = iota;
-                    iota = 0;
-                    n
-                }),
-                Self::PAT_UNPAUSE => {
-                    on = true;
-                    iota += 1;
-                    Some(iota - 1)
-                }
-                Self::PAT_PAUSE => on.then(|| {
-                    on = false;
-                    iota += 1;
-                    iota - 1
-                }),
-                _ => unreachable!(),
+            .map(|c| {
+                Ok(match c {
+                    Self::PAT_ONE_SHOT | Self::PAT_RESTART => {
+                        on = true;
+                        iota = 1;
+                        Some(0)
+                    }
+                    Self::PAT_SUSTAIN => on.then(|| {
+                        iota += 1;
+                        iota - 1
+                    }),
+                    Self::PAT_HALT => on.then(|| {
+                        on = false;
+                        let n = iota;
+                        iota = 0;
+                        n
+                    }),
+                    Self::PAT_UNPAUSE => {
+                        on = true;
+                        iota += 1;
+                        Some(iota - 1)
+                    }
+                    Self::PAT_PAUSE => on.then(|| {
+                        on = false;
+                        iota += 1;
+                        iota - 1
+                    }),
+                    c => {
+                        return Err(P1Error::Sheet(format!(
+                            "\"{c}\" isn't a pattern character, expected one of \"o[] ()\""
+                        )));
+                    }
+                })
             })
             .collect()
     }
 
     pub fn r#loop(&self) -> &(usize, usize) {
         match self {
-            Sheet::Labelled { r#loop, sheet: _ } => &r#loop,
-            Sheet::Indexed { r#loop, sheet: _ } => &r#loop,
+            Sheet::Labelled { r#loop, sheet: _ } => r#loop,
+            Sheet::Indexed { r#loop, sheet: _ } => r#loop,
         }
     }
 
@@ -139,12 +184,22 @@ impl Sheet {
             Sheet::Indexed { r#loop: _, sheet } => sheet.len(),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of columns in the loop, shared by every row without its own length
+    pub fn columns(&self) -> usize {
+        let (loop_start, loop_end) = self.r#loop();
+        loop_end + 1 - loop_start
+    }
 }
 
 impl FromStr for Sheet {
-    type Err = ();
+    type Err = P1Error;
 
-    fn from_str(sheet: &str) -> Result<Self, ()> {
+    fn from_str(sheet: &str) -> Result<Self, P1Error> {
         // Split a string with the given range inclusively, padding whitespace if the range exceeds the string bounds
         fn split_pad_inclusive(input: &str, start: usize, end: usize, c: &char) -> String {
             let input: Vec<_> = input.chars().collect();
@@ -153,7 +208,7 @@ impl FromStr for Sheet {
 
         let mut lines = sheet.lines();
         let Some(first_line) = lines.next() else {
-            return Err(());
+            return Err(P1Error::Sheet("empty sheet".into()));
         };
 
         // Collect lines with non-empty patterns so we can traverse them without consuming them
@@ -161,12 +216,14 @@ impl FromStr for Sheet {
         // TODO allow lines to have comments
         let lines: Vec<_> = lines
             .filter(|line| match line.find(Self::SEPARATOR) {
-                None => line.len() > 0,
+                None => !line.is_empty(),
                 Some(sep_column) => line.len() > sep_column + 1,
             })
             .collect();
 
         let last_column = lines.iter().map(|line| line.len()).max();
+        // Without a loop end, the loop ends with the longest row
+        let no_rows = || P1Error::Sheet("sheet has no rows to find the end of its loop in".into());
         let loop_start_column = first_line.find(Self::LOOP_START);
         let loop_end_column = first_line.find(Self::LOOP_END);
 
@@ -175,37 +232,60 @@ impl FromStr for Sheet {
             // Indexed sheet
             None => {
                 let loop_start = loop_start_column.unwrap_or(0);
-                let loop_end = loop_end_column.unwrap_or(
-                    last_column.expect("Entering this closure => last_column.is_some()") - 1,
-                );
+                let loop_end = match loop_end_column {
+                    Some(loop_end) => loop_end,
+                    None => last_column.ok_or_else(no_rows)? - 1,
+                };
                 Sheet::Indexed {
                     r#loop: (loop_start, loop_end),
                     sheet: lines
                         .into_iter()
                         .map(|line| split_pad_inclusive(line, loop_start, loop_end, &Self::EMPTY))
                         .map(Self::pat_to_source_index_list)
-                        .collect(),
+                        .collect::<Result<_, _>>()?,
                 }
             }
             // Labelled sheet
             Some(sep_column) => {
                 let loop_start = loop_start_column.unwrap_or(sep_column + 1);
-                let loop_end = loop_end_column.unwrap_or(
-                    last_column.expect("Entering this closure => last_column.is_some()") - 1,
-                );
+                let loop_end = match loop_end_column {
+                    Some(loop_end) => loop_end,
+                    None => last_column.ok_or_else(no_rows)? - 1,
+                };
                 Sheet::Labelled {
                     r#loop: (loop_start, loop_end),
                     sheet: lines
                         .into_iter()
                         .map(|line| {
-                            let label = line[0..sep_column].trim();
-                            (
+                            let label = line.get(..sep_column).ok_or_else(|| {
+                                P1Error::Sheet(format!(
+                                    "row \"{line}\" doesn't reach the separator's column"
+                                ))
+                            })?;
+                            let label = label.trim();
+                            let length =
+                                label.rsplit_once(Self::LENGTH).and_then(|(name, length)| {
+                                    Some((name, length.trim().parse::<usize>().ok()?))
+                                });
+                            let (label, end) = match length {
+                                Some((name, 0)) => {
+                                    return Err(P1Error::Sheet(format!(
+                                        "row \"{}\" has a length of 0",
+                                        name.trim()
+                                    )));
+                                }
+                                Some((name, length)) => (name.trim(), loop_start + length - 1),
+                                None => (label, loop_end),
+                            };
+                            Ok((
                                 label.to_string(),
-                                split_pad_inclusive(line, loop_start, loop_end, &Self::EMPTY),
-                            )
+                                split_pad_inclusive(line, loop_start, end, &Self::EMPTY),
+                            ))
+                        })
+                        .map(|row| {
+                            row.and_then(|(k, v)| Ok((k, Self::pat_to_source_index_list(v)?)))
                         })
-                        .map(|(k, v)| (k, Self::pat_to_source_index_list(v)))
-                        .collect(),
+                        .collect::<Result<_, _>>()?,
                 }
             }
         })
@@ -218,8 +298,7 @@ impl FromLua for Sheet {
             .as_string()
             .ok_or(LuaError::RuntimeError("expected table".into()))?
             .to_string_lossy();
-        // TODO HERE: proper error handling for Sheet
-        Ok(Sheet::from_str(&string).map_err(|()| P1Error::Sheet)?)
+        Ok(Sheet::from_str(&string)?)
     }
 }
 
@@ -228,13 +307,91 @@ impl FromLua for Sheet {
 //
 pub type DynInstrument = LuaUserDataRef<Box<dyn types::BiInstrument>>;
 
+/// Borrows an instrument provided from Lua anew, e.g. for every block of a lazy sheet
+///
+/// Built where the instrument is taken from Lua, so code that never sees a Lua value doesn't link
+/// against Lua.
+pub type Reborrow = Box<dyn Fn() -> LuaResult<DynInstrument>>;
+
+/// An instrument provided in an instruments table
+pub enum InstrumentValue {
+    /// An instrument constructed in Rust, along with how to borrow it again and its identity (see
+    /// [`InstrumentValue::id`])
+    UserData(DynInstrument, Reborrow, u64),
+    /// An instrument constructed from the table itself, e.g. one defined in Lua (see
+    /// [`LuaInstrument`])
+    Owned(Box<dyn BiInstrument>),
+}
+
+impl std::ops::Deref for InstrumentValue {
+    type Target = dyn BiInstrument;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            InstrumentValue::UserData(instrument, ..) => &***instrument,
+            InstrumentValue::Owned(instrument) => &**instrument,
+        }
+    }
+}
+
 pub enum Instruments {
-    Labelled(HashMap<String, DynInstrument>),
-    Indexed(Vec<DynInstrument>),
+    Labelled(HashMap<String, InstrumentValue>),
+    Indexed(Vec<InstrumentValue>),
+}
+
+impl InstrumentValue {
+    /// Identity of the instrument, which stays the same for as long as it's alive in Lua and is
+    /// never reused by another
+    ///
+    /// Instruments constructed from a table are built anew for every render, so have none.
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            InstrumentValue::UserData(.., id) => Some(*id),
+            InstrumentValue::Owned(_) => None,
+        }
+    }
+
+    /// The identity of `user_data`, assigned the first time it's provided as an instrument
+    fn user_data_id(user_data: &LuaAnyUserData) -> LuaResult<u64> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        const KEY: &str = "plunder.id";
+
+        if let Some(id) = user_data.named_user_value::<Option<u64>>(KEY)? {
+            return Ok(id);
+        }
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        user_data.set_named_user_value(KEY, id)?;
+        Ok(id)
+    }
 }
 
 impl Instruments {
+    /// The instrument that the row named `name` is routed to
+    pub fn get(&self, name: &str) -> Option<&dyn BiInstrument> {
+        let instrument = match self {
+            Instruments::Labelled(instruments) => instruments.get(name),
+            // Indexed rows are named as they're indexed in Lua
+            Instruments::Indexed(instruments) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| instruments.get(i.checked_sub(1)?)),
+        };
+        instrument.map(|instrument| &**instrument)
+    }
+
+    /// Every instrument along with the name of the rows routed to it
+    pub fn into_named(self) -> Vec<(String, InstrumentValue)> {
+        match self {
+            Instruments::Labelled(instruments) => instruments.into_iter().collect(),
+            Instruments::Indexed(instruments) => (1..)
+                .map(|i: usize| i.to_string())
+                .zip(instruments)
+                .collect(),
+        }
+    }
+
     pub fn from_lua_pairs<E>(
+        lua: &Lua,
         instruments: impl Iterator<Item = Result<(LuaValue, LuaValue), E>>,
     ) -> Result<Option<Self>, P1Error>
     where
@@ -244,15 +401,26 @@ impl Instruments {
 
         #[inline(always)]
         fn lua_value_to_instrument(
+            lua: &Lua,
             lua_value: LuaValue,
             key: Result<String, i64>,
-        ) -> Result<DynInstrument, P1Error> {
-            let LuaValue::UserData(user_data) = lua_value else {
-                return Err(P1Error::InstrumentUnknown(
+        ) -> Result<InstrumentValue, P1Error> {
+            match lua_value {
+                LuaValue::UserData(user_data) => Ok(InstrumentValue::UserData(
+                    user_data.borrow::<Box<dyn BiInstrument>>()?,
+                    {
+                        let user_data = user_data.clone();
+                        Box::new(move || user_data.borrow::<Box<dyn BiInstrument>>())
+                    },
+                    InstrumentValue::user_data_id(&user_data)?,
+                )),
+                lua_value @ (LuaValue::Function(_) | LuaValue::Table(_)) => Ok(
+                    InstrumentValue::Owned(Box::new(LuaInstrument::new(lua, lua_value)?)),
+                ),
+                _ => Err(P1Error::InstrumentUnknown(
                     key.unwrap_or_else(|i| i.to_string()),
-                ));
-            };
-            Ok(user_data.borrow::<Box<dyn BiInstrument>>()?)
+                )),
+            }
         }
 
         let mut collection = None;
@@ -275,12 +443,12 @@ impl Instruments {
                 (Some(Labelled(map)), LuaValue::String(s)) => {
                     _ = map.insert(
                         s.to_string_lossy(),
-                        lua_value_to_instrument(instrument, Ok(s.to_string_lossy()))?,
+                        lua_value_to_instrument(lua, instrument, Ok(s.to_string_lossy()))?,
                     )
                 }
                 // NOTE: ignores pair index (making it possible to have comments and stuff)
                 (Some(Indexed(list)), LuaValue::Integer(i)) => {
-                    list.push(lua_value_to_instrument(instrument, Err(i))?)
+                    list.push(lua_value_to_instrument(lua, instrument, Err(i))?)
                 }
                 (None, _) => unreachable!("previous match ensures `collection` is initialized"),
                 _ => unreachable!("previous match"),
@@ -291,11 +459,11 @@ impl Instruments {
 }
 
 impl FromLua for Instruments {
-    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+    fn from_lua(value: LuaValue, lua: &Lua) -> LuaResult<Self> {
         let table = value
             .as_table()
             .ok_or(LuaError::RuntimeError("expected table".into()))?;
-        Ok(Instruments::from_lua_pairs(table.pairs())
+        Ok(Instruments::from_lua_pairs(lua, table.pairs())
             .map_err(Into::<LuaError>::into)?
             .unwrap_or(Instruments::Indexed(Vec::new())))
     }
@@ -307,11 +475,63 @@ impl FromLua for Instruments {
 #[derive(serde::Deserialize)]
 pub struct Config {
     pub interval: usize,
+    /// Tempo of the sheet in beats per minute, which rows set to `fit` are stretched to
+    #[serde(default)]
+    pub tempo: Option<f32>,
+    /// Fraction of an interval by which every off-beat (odd) column is delayed
+    #[serde(default)]
+    pub swing: f32,
+    #[serde(default)]
+    pub groove: Option<Groove>,
+    /// Overrides for individual rows, keyed by label (or by index, for indexed sheets)
+    #[serde(default)]
+    pub rows: HashMap<String, RowConfig>,
+    /// Compute samples as they're played rather than rendering the whole sheet up front
+    #[serde(default)]
+    pub lazy: bool,
+}
+
+/// Overrides for a single row of a sheet
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct RowConfig {
+    /// Interval of the row's columns, in place of [`Config::interval`]
+    pub interval: Option<usize>,
+    /// Number of columns after which the row loops, in place of the length of its pattern
+    pub length: Option<usize>,
+    /// Factor by which the row's instrument is slowed down (and pitched down), e.g. `2` plays it
+    /// at half speed
+    pub stretch: Option<f32>,
+    /// Stretch the row's instrument from its own tempo to [`Config::tempo`], if both are known
+    #[serde(default)]
+    pub fit: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config { interval: 1000 }
+        Config {
+            interval: 1000,
+            tempo: None,
+            swing: 0.,
+            groove: None,
+            rows: HashMap::new(),
+            lazy: false,
+        }
+    }
+}
+
+impl Config {
+    /// Offset in samples of the start of a column `interval` samples wide, and the velocity of what
+    /// it triggers, after swing and groove are applied
+    pub fn column_offset(&self, column: usize, interval: usize) -> (isize, f32) {
+        let step = self
+            .groove
+            .as_ref()
+            .and_then(|groove| groove.step(column))
+            .copied()
+            .unwrap_or_default();
+        let swing = if column % 2 == 1 { self.swing } else { 0. };
+        let offset = (swing + step.timing) * interval as f32;
+        (offset.round() as isize, step.velocity)
     }
 }
 
@@ -325,6 +545,148 @@ impl FromLua for Config {
 //
 // Rendered Buffer
 //
+
+/// A row of a sheet, routed to the instrument it triggers
+pub struct Row<'a> {
+    pub name: String,
+    pub pat: SourceIndexList,
+    /// Width of each column of the row in samples
+    pub interval: usize,
+    /// Factor by which the instrument is slowed down
+    pub stretch: f64,
+    pub instrument: &'a dyn BiInstrument,
+    /// Identity of the instrument across renders, see [`InstrumentValue::id`]
+    pub id: Option<u64>,
+}
+
+impl<'a> Row<'a> {
+    /// Apply the row's overrides from `config` to its pattern
+    pub fn new(
+        config: &Config,
+        name: String,
+        pat: &SourceIndexList,
+        instrument: &'a dyn BiInstrument,
+    ) -> Self {
+        let row_config = config.rows.get(&name).cloned().unwrap_or_default();
+        let mut pat = pat.clone();
+        if let Some(length) = row_config.length {
+            pat.resize(length, None);
+        }
+        let fit = match (row_config.fit, instrument.tempo(), config.tempo) {
+            (true, Some(from), Some(to)) if from > 0. && to > 0. => Some(from / to),
+            _ => None,
+        };
+        let stretch = row_config.stretch.or(fit).filter(|stretch| *stretch > 0.);
+        Row {
+            name,
+            pat,
+            interval: row_config.interval.unwrap_or(config.interval),
+            stretch: stretch.map_or(1., |stretch| stretch as f64),
+            instrument,
+            id: None,
+        }
+    }
+
+    /// Pair every row of `sheet` with its instrument
+    pub fn route(
+        config: &Config,
+        sheet: &Sheet,
+        instruments: &'a Instruments,
+    ) -> Result<Vec<Self>, P1Error> {
+        let mut rows = match (sheet, instruments) {
+            (Sheet::Labelled { sheet, .. }, Instruments::Labelled(instruments)) => sheet
+                .iter()
+                .map(|(name, pat)| {
+                    let instrument = instruments
+                        .get(name)
+                        .ok_or_else(|| P1Error::UnboundInstrument(name.clone()))?;
+                    Ok(Row {
+                        id: instrument.id(),
+                        ..Row::new(config, name.clone(), pat, &**instrument)
+                    })
+                })
+                .collect::<Result<Vec<_>, P1Error>>()?,
+            (Sheet::Indexed { sheet, .. }, Instruments::Indexed(instruments)) => sheet
+                .iter()
+                .enumerate()
+                .map(|(i, pat)| {
+                    // Indexed rows are named as they're indexed in Lua
+                    let name = (i + 1).to_string();
+                    let instrument = instruments
+                        .get(i)
+                        .ok_or_else(|| P1Error::UnboundInstrument(name.clone()))?;
+                    Ok(Row {
+                        id: instrument.id(),
+                        ..Row::new(config, name, pat, &**instrument)
+                    })
+                })
+                .collect::<Result<Vec<_>, P1Error>>()?,
+            // An empty instruments table can't tell whether it's labelled or indexed
+            (Sheet::Labelled { sheet, .. }, Instruments::Indexed(instruments))
+                if instruments.is_empty() =>
+            {
+                return match sheet.keys().next() {
+                    Some(name) => Err(P1Error::UnboundInstrument(name.clone())),
+                    None => Ok(Vec::new()),
+                };
+            }
+            (Sheet::Labelled { .. }, Instruments::Indexed(_)) => {
+                return Err(P1Error::ArrangementMismatch(false));
+            }
+            (Sheet::Indexed { .. }, Instruments::Labelled(_)) => {
+                return Err(P1Error::ArrangementMismatch(true));
+            }
+        };
+        // Mix rows in a stable order
+        rows.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(rows)
+    }
+
+    /// Number of samples after which the row repeats
+    pub fn cycle(&self) -> usize {
+        self.pat.len() * self.interval
+    }
+
+    /// Split the row's pattern into the runs it plays
+    pub fn runs(&self, config: &Config) -> Vec<Run> {
+        let pat = &self.pat;
+        let mut runs = Vec::new();
+        let mut column = 0;
+        while column < pat.len() {
+            let Some(source) = pat[column] else {
+                column += 1;
+                continue;
+            };
+            let mut end = column + 1;
+            while end < pat.len() && pat[end] == Some(source + end - column) {
+                end += 1;
+            }
+            let (offset, velocity) = config.column_offset(column, self.interval);
+            runs.push(Run {
+                column,
+                source,
+                len: end - column,
+                offset,
+                velocity,
+            });
+            column = end;
+        }
+        runs
+    }
+}
+
+/// A run of columns whose source indices count up from its first column, playing as one
+/// contiguous segment of the instrument from the first column's offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Run {
+    pub column: usize,
+    pub source: usize,
+    /// Number of columns in the run
+    pub len: usize,
+    pub offset: isize,
+    pub velocity: f32,
+}
+
 #[derive(Debug)]
 pub enum P1Buffer {
     Mono(Vec<Sample<1>>),
@@ -332,59 +694,298 @@ pub enum P1Buffer {
 }
 
 impl P1Buffer {
+    pub fn len(&self) -> usize {
+        match self {
+            P1Buffer::Mono(buffer) => buffer.len(),
+            P1Buffer::Stereo(buffer) => buffer.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Length of a buffer spanning both `columns` intervals and every row's cycle
+    pub fn size(config: &Config, columns: usize, rows: &[Row]) -> usize {
+        fn gcd(a: usize, b: usize) -> usize {
+            if b == 0 { a } else { gcd(b, a % b) }
+        }
+
+        // Rows of differing cycles only line up again after the least common multiple of them all
+        rows.iter()
+            .map(Row::cycle)
+            .filter(|cycle| *cycle > 0)
+            .fold(columns * config.interval, |size, cycle| {
+                size / gcd(size, cycle) * cycle
+            })
+    }
+
+    /// Whether `rows` can be mixed in stereo, or else only in mono
+    pub fn stereo(rows: &[Row]) -> Result<bool, P1Error> {
+        if rows
+            .iter()
+            .all(|row| Instrument::<2>::ok(row.instrument).is_ok())
+        {
+            return Ok(true);
+        }
+        for row in rows {
+            Instrument::<1>::ok(row.instrument)
+                .map_err(|error| P1Error::InstrumentNotOk(row.name.clone(), error))?;
+        }
+        Ok(false)
+    }
+
+    /// Mix `rows` into a buffer spanning both `columns` intervals and every row's cycle
+    ///
+    /// The buffer is stereo unless one of the instruments can only produce mono. Rows are mixed
+    /// into row buffers of their own first, which are returned to be reused by the next mix of
+    /// the same sheet through `cache`.
+    pub fn mix(
+        config: &Config,
+        columns: usize,
+        rows: &[Row],
+        cache: Option<&RowBuffers>,
+    ) -> Result<Option<(Self, RowBuffers)>, P1Error> {
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        let size = P1Buffer::size(config, columns, rows);
+        let stereo = P1Buffer::stereo(rows)?;
+
+        let row_buffers: RowBuffers = rows
+            .iter()
+            .map(|row| {
+                let previous = cache.and_then(|cache| cache.get(&row.name));
+                let row_buffer = RowBuffer::render(config, row, stereo, previous);
+                (row.name.clone(), row_buffer)
+            })
+            .collect();
+        // Instruments can fail as they're played (e.g. those defined in Lua), which they report
+        // through `ok`
+        for row in rows {
+            let ok = match stereo {
+                true => Instrument::<2>::ok(row.instrument),
+                false => Instrument::<1>::ok(row.instrument),
+            };
+            ok.map_err(|error| P1Error::InstrumentNotOk(row.name.clone(), error))?;
+        }
+
+        let buffer = match stereo {
+            true => P1Buffer::Stereo(tile(size, row_buffers.values(), |frames| match frames {
+                Frames::Stereo(frames) => frames,
+                Frames::Mono(_) => unreachable!("rows are rendered in stereo"),
+            })),
+            false => P1Buffer::Mono(tile(size, row_buffers.values(), |frames| match frames {
+                Frames::Mono(frames) => frames,
+                Frames::Stereo(_) => unreachable!("rows are rendered in mono"),
+            })),
+        };
+        Ok(Some((buffer, row_buffers)))
+    }
+}
+
+/// Sum row buffers into a buffer of `size`, repeating each one over it
+fn tile<'a, const CHANNELS: usize>(
+    size: usize,
+    row_buffers: impl Iterator<Item = &'a Arc<RowBuffer>>,
+    frames: impl Fn(&Frames) -> &Vec<[f32; CHANNELS]>,
+) -> Vec<Sample<CHANNELS>> {
+    let mut buffer = vec![[0.; CHANNELS]; size];
+    for row_buffer in row_buffers {
+        let frames = frames(&row_buffer.frames);
+        if frames.is_empty() {
+            continue;
+        }
+        for (out, frame) in buffer.iter_mut().zip(frames.iter().cycle()) {
+            for (out, s) in out.iter_mut().zip(frame) {
+                *out += s;
+            }
+        }
+    }
+    buffer.into_iter().map(Sample::F32).collect()
+}
+
+/// Row buffers of a mixed sheet, by row name
+pub type RowBuffers = HashMap<String, Arc<RowBuffer>>;
+
+#[derive(Debug, Clone)]
+pub enum Frames {
+    Mono(Vec<[f32; 1]>),
+    Stereo(Vec<[f32; 2]>),
+}
+
+/// A single row mixed over one cycle of its own
+#[derive(Debug, Clone)]
+pub struct RowBuffer {
+    /// Identity of the instrument the row was rendered from
+    id: Option<u64>,
+    interval: usize,
+    stretch: f64,
+    runs: Vec<Run>,
+    frames: Frames,
+}
+
+impl RowBuffer {
+    /// Render a row, reusing its `previous` row buffer if nothing about the row has changed
     pub fn render(
-        config: Config,
-        sheet: Sheet,
-        instruments: Instruments,
-    ) -> Result<Option<Self>, P1Error> {
-        let mut buffer = None;
+        config: &Config,
+        row: &Row,
+        stereo: bool,
+        previous: Option<&Arc<RowBuffer>>,
+    ) -> Arc<Self> {
+        let runs = row.runs(config);
+        if let Some(previous) = previous
+            && row.id.is_some()
+            && previous.id == row.id
+            && previous.interval == row.interval
+            && previous.stretch == row.stretch
+            && previous.runs == runs
+            && previous.frames_len() == row.cycle()
+            && matches!(previous.frames, Frames::Stereo(_)) == stereo
+        {
+            return previous.clone();
+        }
 
-        match (sheet, instruments) {
-            (Sheet::Labelled { sheet, .. }, Instruments::Labelled(instruments)) => {
-                for (name, pat) in sheet.iter() {
-                    let size = pat.len() * config.interval;
-                    let instrument = instruments
-                        .get(name)
-                        .ok_or_else(|| P1Error::UnboundInstrument(name.clone()))?;
-                    let instrument: &Box<dyn types::BiInstrument> = &*instrument;
-                    let instrument: &dyn types::BiInstrument = instrument.deref();
-                    match (
-                        Instrument::<2>::ok(instrument),
-                        Instrument::<1>::ok(instrument),
-                    ) {
-                        (Ok(()), _) => {
-                            match buffer {
-                                Some(P1Buffer::Mono(_)) => {
-                                    unimplemented!("upgrade mono buffer to stereo")
-                                }
-                                Some(P1Buffer::Stereo(_)) => (),
-                                // Initialize buffer as stereo buffer
-                                None => {
-                                    buffer = Some(P1Buffer::Stereo(Vec::with_capacity(size * 32)))
-                                }
-                            }
-                            let Some(P1Buffer::Stereo(buffer)) = &mut buffer else {
-                                unreachable!("buf should be initialized as stereo at this point")
-                            };
+        let mut frames = match stereo {
+            true => Frames::Stereo(vec![[0.; 2]; row.cycle()]),
+            false => Frames::Mono(vec![[0.; 1]; row.cycle()]),
+        };
+        for run in &runs {
+            match &mut frames {
+                Frames::Mono(frames) => mix_run(frames, row, run, |id, block| {
+                    Instrument::<1>::fill(row.instrument, id, block)
+                }),
+                Frames::Stereo(frames) => mix_run(frames, row, run, |id, block| {
+                    Instrument::<2>::fill(row.instrument, id, block)
+                }),
+            }
+        }
+        Arc::new(RowBuffer {
+            id: row.id,
+            interval: row.interval,
+            stretch: row.stretch,
+            runs,
+            frames,
+        })
+    }
 
-                            for i in 0..size {
-                                buffer.insert(
-                                    i,
-                                    buffer.get(i).map(|_| todo!("blend sample")).unwrap_or(
-                                        instrument.get(0).unwrap_or_else(|| todo!("empty sample")),
-                                    ),
-                                );
-                            }
-                        }
-                        (_, Ok(())) => {}
-                        (Err(_), Err(_)) => todo!(),
-                    }
-                }
+    fn frames_len(&self) -> usize {
+        match &self.frames {
+            Frames::Mono(frames) => frames.len(),
+            Frames::Stereo(frames) => frames.len(),
+        }
+    }
+}
+
+/// Map an id sustained past the end of a looping instrument back into its loop
+fn loop_id(instrument: &dyn BiInstrument, id: usize) -> u32 {
+    match instrument.loop_points() {
+        Some((start, end)) if end > start && id >= end as usize => {
+            let (start, end) = (start as usize, end as usize);
+            (start + (id - start) % (end - start)) as u32
+        }
+        _ => id as u32,
+    }
+}
+
+/// Frame `i` of a row's instrument once slowed down by `stretch`, interpolated between the frames
+/// of the instrument itself as read through `get`
+fn stretched<const CHANNELS: usize>(
+    instrument: &dyn BiInstrument,
+    i: usize,
+    stretch: f64,
+    get: impl Fn(u32) -> Option<[f32; CHANNELS]>,
+) -> Option<[f32; CHANNELS]> {
+    let position = i as f64 / stretch;
+    let id = position as usize;
+    let t = (position - id as f64) as f32;
+    let mut frame = get(loop_id(instrument, id))?;
+    if t > 0. {
+        let next = get(loop_id(instrument, id + 1)).unwrap_or([0.; CHANNELS]);
+        for (s, next) in frame.iter_mut().zip(next) {
+            *s += t * (next - *s);
+        }
+    }
+    Some(frame)
+}
+
+/// Add a run of a row onto the row's buffer
+///
+/// Runs pushed past the end of the buffer wrap around to its start, as the row loops.
+fn mix_run<const CHANNELS: usize>(
+    frames: &mut [[f32; CHANNELS]],
+    row: &Row,
+    run: &Run,
+    fill: impl Fn(u32, &mut [Sample<CHANNELS>]) -> usize,
+) {
+    let size = frames.len() as isize;
+    if size == 0 {
+        return;
+    }
+    let start = (run.column * row.interval) as isize + run.offset;
+    read_run(
+        row.instrument,
+        row.stretch,
+        run.source * row.interval,
+        run.len * row.interval,
+        fill,
+        |i, frame| {
+            let out = &mut frames[(start + i as isize).rem_euclid(size) as usize];
+            for (out, s) in out.iter_mut().zip(frame) {
+                *out += run.velocity * s;
+            }
+        },
+    );
+}
+
+/// Read `len` frames of a row's instrument from frame `source` of its run onwards, handing each
+/// to `add` along with its position from `source`
+///
+/// Reads sustained past the end of a looping instrument (e.g. a nested sheet) wrap around to the
+/// start of its loop, and stop at the end of any other. The instrument is read a block at a time
+/// through `fill`, or a frame at a time when the row is stretched.
+fn read_run<const CHANNELS: usize>(
+    instrument: &dyn BiInstrument,
+    stretch: f64,
+    source: usize,
+    len: usize,
+    fill: impl Fn(u32, &mut [Sample<CHANNELS>]) -> usize,
+    mut add: impl FnMut(usize, [f32; CHANNELS]),
+) {
+    const BLOCK: usize = 4096;
+
+    if stretch != 1. {
+        let get = |id| {
+            let mut frame = [Sample::F32([0.; CHANNELS])];
+            (fill(id, &mut frame) == 1).then(|| frame[0].to_f32())
+        };
+        for i in 0..len {
+            match stretched(instrument, source + i, stretch, get) {
+                Some(frame) => add(i, frame),
+                None => break,
             }
-            (Sheet::Indexed { .. }, Instruments::Indexed(_)) => todo!(),
-            _ => unreachable!(),
         }
-        Ok(buffer)
+        return;
+    }
+
+    let mut block = vec![Sample::F32([0.; CHANNELS]); BLOCK.min(len)];
+    let mut i = 0;
+    while i < len {
+        let id = loop_id(instrument, source + i);
+        // Reads can't continue past the end of a loop, as they wrap around to its start
+        let contiguous = match instrument.loop_points() {
+            Some((loop_start, loop_end)) if loop_end > loop_start => loop_end - id,
+            _ => u32::MAX,
+        };
+        let n = (len - i).min(contiguous as usize).min(block.len());
+        let filled = fill(id, &mut block[..n]);
+        for (j, sample) in block[..filled].iter().enumerate() {
+            add(i + j, sample.to_f32());
+        }
+        if filled < n {
+            break;
+        }
+        i += n;
     }
 }
 
@@ -392,96 +993,489 @@ impl P1Buffer {
 // P1, the Instrument
 //
 #[derive(Clone)]
-pub struct P1(Arc<P1Buffer>);
+enum Buffer {
+    Rendered(Arc<P1Buffer>),
+    Lazy(Rc<LazyBuffer>),
+}
+
+#[derive(Clone)]
+pub struct P1 {
+    buffer: Buffer,
+    rows: Arc<RowBuffers>,
+}
 
 impl P1 {
+    /// Render a sheet, reusing the rows of a `previous` render wherever they're unchanged
+    ///
+    /// Lazy sheets aren't rendered at all until they're played, see [`LazyBuffer`]
     pub fn render(
         config: Config,
         sheet: Sheet,
         instruments: Instruments,
+        previous: Option<&P1>,
     ) -> Result<Option<Self>, P1Error> {
-        Ok(P1Buffer::render(config, sheet, instruments)?.map(|p1_buffer| P1(Arc::new(p1_buffer))))
+        if config.lazy {
+            return Ok(
+                LazyBuffer::new(&config, &sheet, instruments)?.map(|buffer| P1 {
+                    buffer: Buffer::Lazy(Rc::new(buffer)),
+                    rows: Arc::default(),
+                }),
+            );
+        }
+
+        let rows = Row::route(&config, &sheet, &instruments)?;
+        let cache = previous.map(|previous| &*previous.rows);
+        let Some((buffer, rows)) = P1Buffer::mix(&config, sheet.columns(), &rows, cache)? else {
+            return Ok(None);
+        };
+        Ok(Some(P1 {
+            buffer: Buffer::Rendered(Arc::new(buffer)),
+            rows: Arc::new(rows),
+        }))
+    }
+}
+
+impl From<P1Buffer> for P1 {
+    fn from(buffer: P1Buffer) -> Self {
+        P1 {
+            buffer: Buffer::Rendered(Arc::new(buffer)),
+            rows: Arc::default(),
+        }
     }
 }
 
 impl types::Instrument<1> for P1 {
     fn ok(&self) -> Result<(), String> {
-        // let mut inner = self.deref_();
-        // match inner.buffer {
-        //     Some(P1Buffer::Mono(_)) => Ok(()),
-        //     Some(P1Buffer::Stereo(_)) => unimplemented!("downgrade stereo buffer"),
-        //     None => match &inner.sheet {
-        //         // No buffer but sheet has loop - allocate empty buffer of loop size
-        //         //
-        //         // TODO add warning here: sheet present, 0 instruments routed
-        //         Some(sheet) => {
-        //             let (loop_start, loop_end) = sheet.r#loop();
-        //             inner.buffer = Some(P1Buffer::Mono(
-        //                 (*loop_start..=*loop_end)
-        //                     .map(|_| (0..32).map(|_| Sample::F32([0.])))
-        //                     .flatten()
-        //                     .collect(),
-        //             ));
-        //             Ok(())
-        //         }
-        //         None => Err("P1 can't generate any output without a sheet".to_string()),
-        //     },
-        // }
-        Ok(())
+        match &self.buffer {
+            Buffer::Rendered(_) => Ok(()),
+            Buffer::Lazy(buffer) => Instrument::<1>::ok(&**buffer),
+        }
     }
 
     fn get(&self, id: u32) -> Option<Sample<1>> {
-        let P1Buffer::Mono(mono_buffer) = &*self.0 else {
-            unreachable!("if init called, buffer should be initialized as mono")
-        };
-        mono_buffer.get(id as usize).copied()
+        match &self.buffer {
+            Buffer::Rendered(buffer) => match &**buffer {
+                P1Buffer::Mono(buffer) => buffer.get(id as usize).copied(),
+                P1Buffer::Stereo(buffer) => buffer.get(id as usize).copied().map(Sample::to_mono),
+            },
+            Buffer::Lazy(buffer) => Instrument::get(&**buffer, id),
+        }
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        match &self.buffer {
+            Buffer::Rendered(buffer) => match &**buffer {
+                P1Buffer::Mono(buffer) => fill_from_slice(buffer, start, frames, |s| s),
+                P1Buffer::Stereo(buffer) => fill_from_slice(buffer, start, frames, Sample::to_mono),
+            },
+            Buffer::Lazy(buffer) => Instrument::fill(&**buffer, start, frames),
+        }
     }
 }
 
 impl types::Instrument<2> for P1 {
     fn ok(&self) -> Result<(), String> {
-        // let mut inner = self.deref_();
-        // match inner.buffer {
-        //     Some(P1Buffer::Stereo(_)) => Ok(()),
-        //     Some(P1Buffer::Mono(_)) => unimplemented!("upgrade stereo buffer"),
-        //     None => match &inner.sheet {
-        //         // No buffer but sheet has loop - allocate empty buffer of loop size
-        //         //
-        //         // TODO add warning here: sheet present, 0 instruments routed
-        //         Some(sheet) => {
-        //             let (loop_start, loop_end) = sheet.r#loop();
-        //             inner.buffer = Some(P1Buffer::Stereo(
-        //                 (*loop_start..=*loop_end)
-        //                     .map(|_| (0..32).map(|_| Sample::F32([0., 0.])))
-        //                     .flatten()
-        //                     .collect(),
-        //             ));
-        //             Ok(())
-        //         }
-        //         None => Err("P1 can't generate any output without a sheet".to_string()),
-        //     },
-        // }
-        Ok(())
+        match &self.buffer {
+            Buffer::Rendered(_) => Ok(()),
+            Buffer::Lazy(buffer) => Instrument::<2>::ok(&**buffer),
+        }
     }
 
     fn get(&self, id: u32) -> Option<Sample<2>> {
-        let P1Buffer::Stereo(stereo_buffer) = &*self.0 else {
-            unreachable!("if init called, buffer should be initialized as stereo")
-        };
-        stereo_buffer.get(id as usize).copied()
+        match &self.buffer {
+            Buffer::Rendered(buffer) => match &**buffer {
+                P1Buffer::Mono(buffer) => buffer.get(id as usize).copied().map(Sample::to_stereo),
+                P1Buffer::Stereo(buffer) => buffer.get(id as usize).copied(),
+            },
+            Buffer::Lazy(buffer) => Instrument::get(&**buffer, id),
+        }
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        match &self.buffer {
+            Buffer::Rendered(buffer) => match &**buffer {
+                P1Buffer::Mono(buffer) => fill_from_slice(buffer, start, frames, Sample::to_stereo),
+                P1Buffer::Stereo(buffer) => fill_from_slice(buffer, start, frames, |s| s),
+            },
+            Buffer::Lazy(buffer) => Instrument::fill(&**buffer, start, frames),
+        }
     }
 }
 
-impl types::BiInstrument for P1 {}
+impl types::BiInstrument for P1 {
+    /// A sheet loops over its whole buffer, so a sustained trigger of a nested sheet plays its
+    /// loop for as long as it's held
+    fn loop_points(&self) -> Option<(u32, u32)> {
+        Some((0, self.length()?))
+    }
+
+    fn length(&self) -> Option<u32> {
+        let len = match &self.buffer {
+            Buffer::Rendered(buffer) => buffer.len(),
+            Buffer::Lazy(buffer) => buffer.len(),
+        };
+        Some(len as u32)
+    }
+
+    fn channels(&self) -> u16 {
+        let stereo = match &self.buffer {
+            Buffer::Rendered(buffer) => matches!(**buffer, P1Buffer::Stereo(_)),
+            Buffer::Lazy(buffer) => buffer.stereo(),
+        };
+        if stereo { 2 } else { 1 }
+    }
+
+    fn name(&self) -> String {
+        "p1".into()
+    }
+}
 
 impl LuaUserData for P1 {}
 
+impl types::InstrumentFactory for P1 {
+    type Args = (Config, Sheet, Instruments, Option<DynInstrument>);
+    type Instrument = P1;
+    const NAME: &str = "p1.render";
+
+    fn construct((config, sheet, instruments, previous): Self::Args) -> LuaResult<Option<P1>> {
+        // Reuse the rows of the previous render of this sheet
+        let previous = previous.as_ref().and_then(|previous| {
+            let previous: &dyn std::any::Any = &***previous;
+            previous.downcast_ref::<P1>()
+        });
+        Ok(P1::render(config, sheet, instruments, previous)?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use types::test_util::TestInstrument;
+
+    use super::*;
+
+    fn mix_mono(config: &Config, pat: &str, instrument: &dyn BiInstrument) -> Vec<f32> {
+        let pat = Sheet::pat_to_source_index_list(pat).unwrap();
+        let rows = [Row::new(config, "ramp".into(), &pat, instrument)];
+        let (buffer, _) = P1Buffer::mix(config, pat.len(), &rows, None)
+            .unwrap()
+            .unwrap();
+        let p1 = P1::from(buffer);
+        (0..)
+            .map_while(|id| Instrument::<1>::get(&p1, id))
+            .map(|s| s.to_f32()[0])
+            .collect()
+    }
+
+    #[test]
+    fn mix_swing() {
+        let config = Config {
+            interval: 4,
+            swing: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(
+            mix_mono(&config, "oooo", &TestInstrument::ramp(4)).as_slice(),
+            &[
+                4., 6., 3., 4., 0., 0., 1., 2., 4., 6., 3., 4., 0., 0., 1., 2.
+            ],
+        );
+    }
+
+    #[test]
+    fn mix_groove() {
+        let groove = Groove::from_sheet(
+            "         | [  ]\n\
+             timing   |  5\n\
+             velocity | 9 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            groove,
+            Groove(vec![
+                GrooveStep {
+                    timing: 0.,
+                    velocity: 1.
+                },
+                GrooveStep {
+                    timing: 0.5,
+                    velocity: 1.
+                },
+                GrooveStep {
+                    timing: 0.,
+                    velocity: 0.
+                },
+                GrooveStep::default(),
+            ]),
+        );
+        let config = Config {
+            interval: 2,
+            groove: Some(groove),
+            ..Default::default()
+        };
+        assert_eq!(
+            mix_mono(&config, "o o ", &TestInstrument::ramp(2)).as_slice(),
+            &[1., 2., 0., 0., 0., 0., 0., 0.],
+        );
+        assert_eq!(
+            mix_mono(&config, "oooo", &TestInstrument::ramp(2)).as_slice(),
+            &[1., 2., 0., 1., 2., 0., 1., 2.],
+        );
+    }
+
+    #[test]
+    fn sheet_typos() {
+        let error = |sheet| matches!(Sheet::from_str(sheet), Err(P1Error::Sheet(_)));
+        // Characters which aren't part of a pattern
+        assert!(error("       | [  ]\nsnare  |   \"   \"\n"));
+        assert!(error("o x o\n"));
+        // No rows to end the loop with
+        assert!(error("       | [\n"));
+        assert!(error("[\n"));
+        // Rows not reaching the separator, or cut through a character by it
+        assert!(error("       | [  ]\nkick\n"));
+        assert!(error(" | [ ]\né| o\n"));
+    }
+
+    #[test]
+    fn mix_polymeter() {
+        let sheet = Sheet::from_str(
+            "       | [  ]\n\
+             kick   | o o\n\
+             perc/3 | o\n",
+        )
+        .unwrap();
+        let Sheet::Labelled { sheet: pats, .. } = &sheet else {
+            panic!("expected a labelled sheet");
+        };
+        assert_eq!(pats["perc"], vec![Some(0), Some(1), Some(2)]);
+
+        // Only numbers are lengths
+        let slashed = Sheet::from_str("       | [  ]\nhi/hat | o o\n").unwrap();
+        let Sheet::Labelled { sheet: slashed, .. } = &slashed else {
+            panic!("expected a labelled sheet");
+        };
+        assert_eq!(slashed["hi/hat"].len(), 4);
+        assert!(matches!(
+            Sheet::from_str("       | [  ]\nperc/0 | o\n"),
+            Err(P1Error::Sheet(_))
+        ));
+
+        let config = Config {
+            interval: 1,
+            ..Default::default()
+        };
+        let ramp = TestInstrument::ramp(1);
+        let rows: Vec<_> = ["kick", "perc"]
+            .into_iter()
+            .map(|name| Row::new(&config, name.into(), &pats[name], &ramp))
+            .collect();
+        let Some((P1Buffer::Mono(buffer), _)) =
+            P1Buffer::mix(&config, sheet.columns(), &rows, None).unwrap()
+        else {
+            panic!("expected a mono buffer");
+        };
+        assert_eq!(
+            buffer.iter().map(|s| s.to_f32()[0]).collect::<Vec<_>>(),
+            vec![2., 0., 1., 1., 1., 0., 2., 0., 1., 1., 1., 0.],
+        );
+    }
+
+    #[test]
+    fn mix_nested() {
+        let config = Config {
+            interval: 1,
+            ..Default::default()
+        };
+        let inner = P1::from(P1Buffer::Mono(
+            [1., 2., 3.].map(|s| Sample::F32([s])).to_vec(),
+        ));
+        assert_eq!(
+            mix_mono(&config, "[     ] o", &inner).as_slice(),
+            &[1., 2., 3., 1., 2., 3., 1., 0., 1.],
+        );
+    }
+
+    #[test]
+    fn mix_failing() {
+        let config = Config {
+            interval: 2,
+            ..Default::default()
+        };
+        let pat = Sheet::pat_to_source_index_list("o").unwrap();
+        // Fails once it's played past its first frame
+        let failing = TestInstrument {
+            fails_past: Some(0),
+            ..TestInstrument::new(vec![1.])
+        };
+        let rows = [Row::new(&config, "failing".into(), &pat, &failing)];
+        assert!(matches!(
+            P1Buffer::mix(&config, pat.len(), &rows, None),
+            Err(P1Error::InstrumentNotOk(name, _)) if name == "failing"
+        ));
+    }
+
+    #[test]
+    fn fill_matches_get() {
+        let p1 = P1::from(P1Buffer::Mono(
+            (1..=5).map(|s| Sample::F32([s as f32])).collect(),
+        ));
+        for instrument in [&p1 as &dyn BiInstrument, &TestInstrument::ramp(5)] {
+            let mut frames = [Sample::F32([0.]); 4];
+            assert_eq!(Instrument::<1>::fill(instrument, 3, &mut frames), 2);
+            assert_eq!(
+                frames[..2]
+                    .iter()
+                    .map(|s| s.to_f32()[0])
+                    .collect::<Vec<_>>(),
+                vec![4., 5.],
+            );
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let p1 = P1::from(P1Buffer::Mono(vec![Sample::F32([0.]); 5]));
+        assert_eq!(p1.length(), Some(5));
+        assert_eq!(p1.loop_points(), Some((0, 5)));
+        assert_eq!(p1.channels(), 1);
+        assert_eq!(TestInstrument::ramp(5).length(), None);
+        assert_eq!(TestInstrument::ramp(5).channels(), 1);
+    }
+
+    #[test]
+    fn mix_incremental() {
+        let config = Config {
+            interval: 2,
+            swing: 0.5,
+            ..Default::default()
+        };
+        let (kick, hat) = (TestInstrument::ramp(3), TestInstrument::ramp(1));
+        let mix = |pats: [&str; 2], cache| {
+            let pats = pats.map(|pat| Sheet::pat_to_source_index_list(pat).unwrap());
+            let rows = [
+                Row {
+                    id: Some(0),
+                    ..Row::new(&config, "kick".into(), &pats[0], &kick)
+                },
+                Row {
+                    id: Some(1),
+                    ..Row::new(&config, "hat".into(), &pats[1], &hat)
+                },
+            ];
+            let Some((P1Buffer::Mono(buffer), row_buffers)) =
+                P1Buffer::mix(&config, 4, &rows, cache).unwrap()
+            else {
+                panic!("expected a mono buffer");
+            };
+            let buffer: Vec<_> = buffer.iter().map(|s| s.to_f32()[0]).collect();
+            (buffer, row_buffers)
+        };
+
+        let (_, before) = mix(["o o ", "oooo"], None);
+        let (after, row_buffers) = mix(["o  o", "oooo"], Some(&before));
+        assert!(Arc::ptr_eq(&before["hat"], &row_buffers["hat"]));
+        assert!(!Arc::ptr_eq(&before["kick"], &row_buffers["kick"]));
+        assert_eq!(after, mix(["o  o", "oooo"], None).0);
+    }
+
+    #[test]
+    fn mix_lazy() {
+        let config = Config {
+            interval: 3,
+            swing: 0.5,
+            ..Default::default()
+        };
+        let ramp = TestInstrument::ramp(5);
+        let pat = Sheet::pat_to_source_index_list("o  oo [  ]").unwrap();
+        let row = Row::new(&config, "ramp".into(), &pat, &ramp);
+        let (P1Buffer::Mono(eager), _) =
+            P1Buffer::mix(&config, pat.len(), std::slice::from_ref(&row), None)
+                .unwrap()
+                .unwrap()
+        else {
+            panic!("expected a mono buffer");
+        };
+
+        // Compute the row in uneven blocks
+        let row = LazyRow::new(&config, &row);
+        let mut lazy = vec![[0.; 1]; eager.len()];
+        for (i, block) in lazy.chunks_mut(7).enumerate() {
+            row.mix_block(&ramp, i * 7, block, |id, block| {
+                Instrument::<1>::fill(&ramp, id, block)
+            });
+        }
+        assert_eq!(lazy, eager.iter().map(|s| s.to_f32()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mix_stretch() {
+        let config = Config {
+            interval: 4,
+            rows: HashMap::from([(
+                "ramp".into(),
+                RowConfig {
+                    stretch: Some(2.),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let ramp = TestInstrument::ramp(4);
+        assert_eq!(
+            mix_mono(&config, "[  ]", &ramp).as_slice(),
+            &[
+                1., 1.5, 2., 2.5, 3., 3.5, 4., 2., 0., 0., 0., 0., 0., 0., 0., 0.
+            ],
+        );
+
+        let pat = Sheet::pat_to_source_index_list("[  ]").unwrap();
+        let row = Row::new(&config, "ramp".into(), &pat, &ramp);
+        let row = LazyRow::new(&config, &row);
+        let mut lazy = vec![[0.; 1]; 16];
+        row.mix_block(&ramp, 0, &mut lazy, |id, block| {
+            Instrument::<1>::fill(&ramp, id, block)
+        });
+        assert_eq!(lazy[..8], [1., 1.5, 2., 2.5, 3., 3.5, 4., 2.].map(|s| [s]));
+    }
+
+    #[test]
+    fn fit_to_tempo() {
+        // A loop recorded at 90BPM
+        let r#loop = TestInstrument {
+            tempo: Some(90.),
+            ..TestInstrument::ramp(4)
+        };
+        let mut config = Config {
+            tempo: Some(120.),
+            rows: HashMap::from([(
+                "loop".into(),
+                RowConfig {
+                    fit: true,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let pat = Sheet::pat_to_source_index_list("o").unwrap();
+        assert_eq!(
+            Row::new(&config, "loop".into(), &pat, &r#loop).stretch,
+            0.75
+        );
+        // Rows only fit to their instrument's tempo when asked to
+        assert_eq!(Row::new(&config, "other".into(), &pat, &r#loop).stretch, 1.);
+        assert_eq!(
+            Row::new(&config, "loop".into(), &pat, &TestInstrument::ramp(4)).stretch,
+            1.
+        );
+        config.tempo = None;
+        assert_eq!(Row::new(&config, "loop".into(), &pat, &r#loop).stretch, 1.);
+    }
+
     #[test]
     fn pat_to_source_index_list() {
         fn pat_to_source_index_list(s: &str) -> Vec<isize> {
             super::Sheet::pat_to_source_index_list(s)
+                .unwrap()
                 .into_iter()
                 .map(|s| s.map(|u| u as _).unwrap_or(-1))
                 .collect()
@@ -0,0 +1,209 @@
+//! Instruments defined in Lua
+
+use std::{cell::RefCell, rc::Rc};
+
+use types::*;
+
+/// Frames starting at an id, stored as stereo whatever the instrument's channels
+type Block = (u32, Rc<Vec<[f32; 2]>>);
+
+/// An instrument defined in Lua, as either
+/// - a function `get(id)`, or
+/// - a table with a method `:get(id)`, and optionally `:len()` and a `name`
+///
+/// `get` returns a number for a mono frame, `{left, right}` for a stereo frame, or `nil` past the
+/// end of the instrument. Ids count from 0, like those of every other instrument.
+///
+/// Frames are fetched from Lua a block at a time, in a single call into Lua per block, and the
+/// most recent block is kept to serve reads which don't come in order.
+pub struct LuaInstrument {
+    get: LuaFunction,
+    this: Option<LuaTable>,
+    /// Calls `get` for each id of a block from within Lua, see [`LuaInstrument::BATCH`]
+    batch: LuaFunction,
+    length: Option<u32>,
+    channels: u16,
+    name: String,
+    block: RefCell<Option<Block>>,
+    /// The first error raised by `get`, reported through `ok` from then on
+    error: RefCell<Option<String>>,
+}
+
+impl LuaInstrument {
+    const BLOCK: u32 = 1024;
+
+    const BATCH: &str = r#"
+        local get, this, start, n = ...
+        local frames = {}
+        for i = 0, n - 1 do
+          local frame
+          if this then frame = get(this, start + i) else frame = get(start + i) end
+          if frame == nil then break end
+          frames[i + 1] = frame
+        end
+        return frames
+    "#;
+
+    pub fn new(lua: &Lua, value: LuaValue) -> LuaResult<Self> {
+        let (get, this) = match value {
+            LuaValue::Function(get) => (get, None),
+            LuaValue::Table(table) => (table.get::<LuaFunction>("get")?, Some(table)),
+            value => {
+                return Err(LuaError::RuntimeError(format!(
+                    "expected a function or a table with a `get` method, got {}",
+                    value.type_name()
+                )));
+            }
+        };
+        let length = match &this {
+            Some(this) => match this.get::<Option<LuaFunction>>("len")? {
+                Some(len) => Some(len.call::<u32>(this)?),
+                None => None,
+            },
+            None => None,
+        };
+        let name = match &this {
+            Some(this) => this.get::<Option<String>>("name")?,
+            None => None,
+        };
+        let mut instrument = LuaInstrument {
+            get,
+            this,
+            batch: lua
+                .load(Self::BATCH)
+                .set_name("=LuaInstrument")
+                .into_function()?,
+            length,
+            channels: 1,
+            name: name.unwrap_or_else(|| "lua".into()),
+            block: RefCell::new(None),
+            error: RefCell::new(None),
+        };
+        // Frames are mono unless the instrument starts with a stereo one
+        let first = instrument.call_batch(0, 1)?;
+        if let Some(LuaValue::Table(_)) = first.first() {
+            instrument.channels = 2;
+        }
+        Ok(instrument)
+    }
+
+    fn call_batch(&self, start: u32, n: u32) -> LuaResult<Vec<LuaValue>> {
+        self.batch
+            .call::<LuaTable>((self.get.clone(), self.this.clone(), start, n))?
+            .sequence_values()
+            .collect()
+    }
+
+    /// The block starting at `start`, shorter than [`LuaInstrument::BLOCK`] if the instrument ends
+    /// within it
+    ///
+    /// Errors raised by the instrument end it, as there's no way to surface them mid-render, and
+    /// are reported through `ok` instead.
+    fn block(&self, start: u32) -> Rc<Vec<[f32; 2]>> {
+        if let Some((cached, frames)) = &*self.block.borrow()
+            && *cached == start
+        {
+            return frames.clone();
+        }
+        let n = match self.length {
+            Some(length) => Self::BLOCK.min(length.saturating_sub(start)),
+            None => Self::BLOCK,
+        };
+        let frames = self.call_batch(start, n).unwrap_or_else(|error| {
+            self.error.borrow_mut().get_or_insert(error.to_string());
+            Vec::new()
+        });
+        let frames: Vec<_> = frames
+            .into_iter()
+            .map_while(|frame| match frame {
+                LuaValue::Number(s) => Some([s as f32; 2]),
+                LuaValue::Integer(s) => Some([s as f32; 2]),
+                LuaValue::Table(frame) => Some([frame.get(1).ok()?, frame.get(2).ok()?]),
+                _ => None,
+            })
+            .collect();
+        let frames = Rc::new(frames);
+        *self.block.borrow_mut() = Some((start, frames.clone()));
+        frames
+    }
+
+    /// The error raised by `get`, if it's raised one
+    fn raised(&self) -> Result<(), String> {
+        match &*self.error.borrow() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn fill_with<const CHANNELS: usize>(
+        &self,
+        start: u32,
+        frames: &mut [Sample<CHANNELS>],
+        f: impl Fn([f32; 2]) -> Sample<CHANNELS>,
+    ) -> usize {
+        let mut filled = 0;
+        while filled < frames.len() {
+            let id = start + filled as u32;
+            let block_start = id - id % Self::BLOCK;
+            let block = self.block(block_start);
+            let offset = (id - block_start) as usize;
+            let read = block.get(offset..).unwrap_or_default();
+            let n = read.len().min(frames.len() - filled);
+            for (frame, read) in frames[filled..filled + n].iter_mut().zip(read) {
+                *frame = f(*read);
+            }
+            filled += n;
+            // The instrument ended within this block
+            if offset + n < Self::BLOCK as usize && filled < frames.len() {
+                break;
+            }
+        }
+        filled
+    }
+}
+
+impl Instrument<1> for LuaInstrument {
+    fn ok(&self) -> Result<(), String> {
+        // Stereo frames are downmixed to mono
+        self.raised()
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        let mut frame = [Sample::F32([0.])];
+        (Instrument::<1>::fill(self, id, &mut frame) == 1).then_some(frame[0])
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        self.fill_with(start, frames, |frame| Sample::F32(frame).to_mono())
+    }
+}
+
+impl Instrument<2> for LuaInstrument {
+    fn ok(&self) -> Result<(), String> {
+        // Mono frames are upmixed to stereo
+        self.raised()
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        let mut frame = [Sample::F32([0.; 2])];
+        (Instrument::<2>::fill(self, id, &mut frame) == 1).then_some(frame[0])
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        self.fill_with(start, frames, Sample::F32)
+    }
+}
+
+impl BiInstrument for LuaInstrument {
+    fn length(&self) -> Option<u32> {
+        self.length
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
@@ -0,0 +1,436 @@
+//! `plugin`, loading instruments for Plunder from shared libraries at runtime
+//!
+//! A plugin is a shared library exporting an [`ENTRY_POINT`] function, which returns a
+//! [`PluginDescriptor`] listing the instruments it provides. Everything crossing the library
+//! boundary is `#[repr(C)]`, so plugins can be written in any language, and against any Rust
+//! compiler version.
+
+use std::{
+    ffi::{CStr, CString, c_char, c_void},
+    fmt,
+    path::Path,
+    rc::Rc,
+    sync::Arc,
+};
+
+use types::*;
+
+mod library;
+use library::Library;
+
+/// Version of the plugin ABI, bumped whenever the descriptors change
+pub const ABI_VERSION: u32 = 1;
+
+/// Name of the function every plugin exports, of type [`EntryPoint`]
+pub const ENTRY_POINT: &CStr = c"plunder_plugin";
+
+pub type EntryPoint = unsafe extern "C" fn() -> *const PluginDescriptor;
+
+#[repr(C)]
+pub struct PluginDescriptor {
+    /// The [`ABI_VERSION`] the plugin was built against
+    pub abi_version: u32,
+    pub instruments: *const InstrumentDescriptor,
+    pub instruments_len: usize,
+}
+
+/// The constructor and methods of one kind of instrument provided by a plugin
+///
+/// Instruments are opaque pointers owned by the plugin, which are only ever passed back to the
+/// functions of the descriptor that created them, and may be used from multiple threads.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct InstrumentDescriptor {
+    /// Name of the instrument's constructor in Lua, as a nul-terminated string
+    pub name: *const c_char,
+    /// Construct an instrument from a nul-terminated argument string (null if none is given),
+    /// returning null if it can't be constructed
+    pub new: unsafe extern "C" fn(args: *const c_char) -> *mut c_void,
+    pub free: unsafe extern "C" fn(instrument: *mut c_void),
+    /// Number of channels in each frame written by `fill`, either 1 or 2
+    pub channels: unsafe extern "C" fn(instrument: *const c_void) -> u16,
+    /// Length in frames, or a negative number if it isn't known
+    pub length: unsafe extern "C" fn(instrument: *const c_void) -> i64,
+    /// Sample rate in Hz, or 0 if the instrument doesn't have one
+    pub sample_rate: unsafe extern "C" fn(instrument: *const c_void) -> u32,
+    /// Write up to `len` frames from frame `start` onwards into `frames` as interleaved `f32`s
+    /// normalized to `-1.0..=1.0`, returning how many were written before the instrument ran out
+    pub fill: unsafe extern "C" fn(
+        instrument: *const c_void,
+        start: u32,
+        frames: *mut f32,
+        len: usize,
+    ) -> usize,
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    Open(String),
+    Symbol(String),
+    AbiVersion(u32),
+    Descriptor(String),
+    UnknownInstrument(String),
+    Construct(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::Open(error) => write!(f, "Couldn't open plugin: {error}"),
+            PluginError::Symbol(name) => write!(f, "Plugin doesn't export \"{name}\""),
+            PluginError::AbiVersion(version) => write!(
+                f,
+                "Plugin was built against ABI version {version}, expected {ABI_VERSION}"
+            ),
+            PluginError::Descriptor(error) => write!(f, "Plugin's descriptor is invalid: {error}"),
+            PluginError::UnknownInstrument(name) => {
+                write!(f, "Plugin doesn't provide an instrument \"{name}\"")
+            }
+            PluginError::Construct(name) => write!(f, "Plugin failed to construct \"{name}\""),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl From<PluginError> for LuaError {
+    fn from(value: PluginError) -> Self {
+        LuaError::ExternalError(Arc::new(value))
+    }
+}
+
+//
+// Plugin
+//
+pub struct Plugin {
+    instruments: Vec<Rc<Kind>>,
+    /// The library the instruments' functions live in, kept open for as long as anything refers
+    /// to it
+    library: Option<Arc<Library>>,
+}
+
+/// One kind of instrument provided by a plugin, read from its descriptor once loaded
+struct Kind {
+    name: String,
+    descriptor: InstrumentDescriptor,
+}
+
+impl Plugin {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PluginError> {
+        let library = Library::open(path.as_ref())?;
+        // SAFETY: plugins export `ENTRY_POINT` as an `EntryPoint`
+        let entry_point: EntryPoint = unsafe { std::mem::transmute(library.symbol(ENTRY_POINT)?) };
+        // SAFETY: see above
+        let descriptor = unsafe { entry_point() };
+        if descriptor.is_null() {
+            return Err(PluginError::Descriptor("entry point returned null".into()));
+        }
+        // SAFETY: the descriptor's functions stay valid for as long as the library is open, which
+        // the plugin and its instruments keep it
+        let plugin = unsafe { Plugin::from_descriptor(&*descriptor) }?;
+        Ok(Plugin {
+            library: Some(Arc::new(library)),
+            ..plugin
+        })
+    }
+
+    /// A plugin linked into the current process
+    ///
+    /// # Safety
+    ///
+    /// `descriptor` must point to `instruments_len` valid [`InstrumentDescriptor`]s, whose names
+    /// are nul-terminated strings and whose functions stay valid for as long as the plugin or
+    /// any instrument it constructs is alive
+    pub unsafe fn from_descriptor(descriptor: &PluginDescriptor) -> Result<Self, PluginError> {
+        if descriptor.abi_version != ABI_VERSION {
+            return Err(PluginError::AbiVersion(descriptor.abi_version));
+        }
+        let descriptors = match descriptor.instruments_len {
+            0 => &[],
+            _ if descriptor.instruments.is_null() => {
+                return Err(PluginError::Descriptor("instruments are null".into()));
+            }
+            // SAFETY: upheld by the caller
+            len => unsafe { std::slice::from_raw_parts(descriptor.instruments, len) },
+        };
+        let instruments = descriptors
+            .iter()
+            .map(|descriptor| {
+                if descriptor.name.is_null() {
+                    return Err(PluginError::Descriptor("instrument name is null".into()));
+                }
+                // SAFETY: upheld by the caller
+                let name = unsafe { CStr::from_ptr(descriptor.name) };
+                Ok(Rc::new(Kind {
+                    name: name.to_string_lossy().into_owned(),
+                    descriptor: *descriptor,
+                }))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Plugin {
+            instruments,
+            library: None,
+        })
+    }
+
+    /// Names of the instruments the plugin provides
+    pub fn instruments(&self) -> impl Iterator<Item = &str> {
+        self.instruments.iter().map(|kind| kind.name.as_str())
+    }
+
+    pub fn construct(&self, name: &str, args: Option<&str>) -> Result<Foreign, PluginError> {
+        let kind = self
+            .instruments
+            .iter()
+            .find(|kind| kind.name == name)
+            .ok_or_else(|| PluginError::UnknownInstrument(name.into()))?;
+        let args = args
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| PluginError::Construct(name.into()))?;
+        // SAFETY: `args` is null or a valid nul-terminated string
+        let handle = unsafe {
+            (kind.descriptor.new)(args.as_ref().map_or(std::ptr::null(), |a| a.as_ptr()))
+        };
+        if handle.is_null() {
+            return Err(PluginError::Construct(name.into()));
+        }
+        Ok(Foreign {
+            handle,
+            kind: kind.clone(),
+            _library: self.library.clone(),
+        })
+    }
+
+    /// A table of Lua constructors, one for each instrument the plugin provides
+    pub fn into_lua_table(self, lua: &Lua) -> LuaResult<LuaTable> {
+        let plugin = Rc::new(self);
+        let table = lua.create_table()?;
+        for name in plugin.instruments() {
+            let plugin = plugin.clone();
+            let name = name.to_string();
+            table.set(
+                name.clone(),
+                lua.create_function(move |_, args: Option<String>| {
+                    let instrument = plugin.construct(&name, args.as_deref())?;
+                    Ok(Box::new(instrument) as Box<dyn BiInstrument>)
+                })?,
+            )?;
+        }
+        Ok(table)
+    }
+}
+
+//
+// Foreign, the Instrument
+//
+
+/// An instrument constructed by a plugin
+pub struct Foreign {
+    handle: *mut c_void,
+    kind: Rc<Kind>,
+    _library: Option<Arc<Library>>,
+}
+
+impl Foreign {
+    fn channels(&self) -> usize {
+        // SAFETY: the handle was created by this descriptor and hasn't been freed
+        match unsafe { (self.kind.descriptor.channels)(self.handle) } {
+            1 => 1,
+            _ => 2,
+        }
+    }
+
+    /// Read up to `len` frames from `start` onwards as interleaved `f32`s in the instrument's own
+    /// channel count, returning the number of frames read
+    fn read(&self, start: u32, len: usize) -> (Vec<f32>, usize) {
+        let mut frames = vec![0.; len * self.channels()];
+        // SAFETY: `frames` holds `len` frames of the instrument's channel count
+        let read =
+            unsafe { (self.kind.descriptor.fill)(self.handle, start, frames.as_mut_ptr(), len) };
+        (frames, read.min(len))
+    }
+}
+
+impl Drop for Foreign {
+    fn drop(&mut self) {
+        // SAFETY: the handle was created by this descriptor and is never used again
+        unsafe { (self.kind.descriptor.free)(self.handle) }
+    }
+}
+
+impl Instrument<1> for Foreign {
+    fn ok(&self) -> Result<(), String> {
+        // Stereo audio is downmixed to mono
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        let mut frame = [Sample::F32([0.])];
+        (Instrument::<1>::fill(self, id, &mut frame) == 1).then_some(frame[0])
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        let (read, len) = self.read(start, frames.len());
+        let channels = self.channels();
+        for (frame, read) in frames.iter_mut().zip(read.chunks(channels)).take(len) {
+            *frame = match read {
+                [s] => Sample::F32([*s]),
+                [l, r] => Sample::F32([*l, *r]).to_mono(),
+                _ => unreachable!("instruments have 1 or 2 channels"),
+            };
+        }
+        len
+    }
+}
+
+impl Instrument<2> for Foreign {
+    fn ok(&self) -> Result<(), String> {
+        // Mono audio is upmixed to stereo
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        let mut frame = [Sample::F32([0.; 2])];
+        (Instrument::<2>::fill(self, id, &mut frame) == 1).then_some(frame[0])
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        let (read, len) = self.read(start, frames.len());
+        let channels = self.channels();
+        for (frame, read) in frames.iter_mut().zip(read.chunks(channels)).take(len) {
+            *frame = match read {
+                [s] => Sample::F32([*s]).to_stereo(),
+                [l, r] => Sample::F32([*l, *r]),
+                _ => unreachable!("instruments have 1 or 2 channels"),
+            };
+        }
+        len
+    }
+}
+
+impl BiInstrument for Foreign {
+    fn length(&self) -> Option<u32> {
+        // SAFETY: the handle was created by this descriptor and hasn't been freed
+        let length = unsafe { (self.kind.descriptor.length)(self.handle) };
+        u32::try_from(length).ok()
+    }
+
+    fn channels(&self) -> u16 {
+        Foreign::channels(self) as u16
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        // SAFETY: the handle was created by this descriptor and hasn't been freed
+        match unsafe { (self.kind.descriptor.sample_rate)(self.handle) } {
+            0 => None,
+            sample_rate => Some(sample_rate),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.kind.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A mono plugin instrument playing `1, 2, .., len`, constructed from its length
+    unsafe extern "C" fn new(args: *const c_char) -> *mut c_void {
+        let Some(len) = (!args.is_null())
+            .then(|| {
+                unsafe { CStr::from_ptr(args) }
+                    .to_str()
+                    .ok()?
+                    .parse::<u32>()
+                    .ok()
+            })
+            .flatten()
+        else {
+            return std::ptr::null_mut();
+        };
+        Box::into_raw(Box::new(len)).cast()
+    }
+
+    unsafe extern "C" fn free(instrument: *mut c_void) {
+        drop(unsafe { Box::from_raw(instrument.cast::<u32>()) });
+    }
+
+    unsafe extern "C" fn channels(_: *const c_void) -> u16 {
+        1
+    }
+
+    unsafe extern "C" fn length(instrument: *const c_void) -> i64 {
+        unsafe { *instrument.cast::<u32>() as i64 }
+    }
+
+    unsafe extern "C" fn sample_rate(_: *const c_void) -> u32 {
+        0
+    }
+
+    unsafe extern "C" fn fill(
+        instrument: *const c_void,
+        start: u32,
+        frames: *mut f32,
+        len: usize,
+    ) -> usize {
+        let total = unsafe { *instrument.cast::<u32>() };
+        let frames = unsafe { std::slice::from_raw_parts_mut(frames, len) };
+        let mut written = 0;
+        for (id, frame) in (start..total).zip(frames) {
+            *frame = (id + 1) as f32;
+            written += 1;
+        }
+        written
+    }
+
+    fn plugin(abi_version: u32) -> Result<Plugin, PluginError> {
+        let instruments = [InstrumentDescriptor {
+            name: c"ramp".as_ptr(),
+            new,
+            free,
+            channels,
+            length,
+            sample_rate,
+            fill,
+        }];
+        let descriptor = PluginDescriptor {
+            abi_version,
+            instruments: instruments.as_ptr(),
+            instruments_len: instruments.len(),
+        };
+        unsafe { Plugin::from_descriptor(&descriptor) }
+    }
+
+    #[test]
+    fn foreign_instrument() {
+        assert!(matches!(
+            plugin(ABI_VERSION + 1),
+            Err(PluginError::AbiVersion(_))
+        ));
+
+        let plugin = plugin(ABI_VERSION).unwrap();
+        assert!(matches!(
+            plugin.construct("ramp", None),
+            Err(PluginError::Construct(_))
+        ));
+        assert!(matches!(
+            plugin.construct("saw", Some("3")),
+            Err(PluginError::UnknownInstrument(_))
+        ));
+
+        let ramp = plugin.construct("ramp", Some("3")).unwrap();
+        assert_eq!(ramp.length(), Some(3));
+        assert_eq!(BiInstrument::channels(&ramp), 1);
+        assert_eq!(ramp.sample_rate(), None);
+        let mut frames = [Sample::F32([0.; 2]); 4];
+        assert_eq!(Instrument::<2>::fill(&ramp, 1, &mut frames), 2);
+        assert_eq!(
+            frames[..2].iter().map(Sample::to_f32).collect::<Vec<_>>(),
+            vec![[2., 2.], [3., 3.]],
+        );
+        assert!(Instrument::<1>::get(&ramp, 3).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+//! Shared libraries opened at runtime
+
+use std::{
+    ffi::{CStr, CString, c_void},
+    path::Path,
+};
+
+use crate::PluginError;
+
+/// An open shared library, closed once dropped
+pub struct Library(*mut c_void);
+
+// SAFETY: the dynamic linker's handles can be used and closed from any thread
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+#[cfg(unix)]
+impl Library {
+    pub fn open(path: &Path) -> Result<Self, PluginError> {
+        use std::os::unix::ffi::OsStrExt as _;
+
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| PluginError::Open("path contains a nul byte".into()))?;
+        // SAFETY: `path` is a valid nul-terminated string
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(PluginError::Open(Self::error()));
+        }
+        Ok(Library(handle))
+    }
+
+    /// Address of the symbol `name`, which is only valid for as long as the library is open
+    pub fn symbol(&self, name: &CStr) -> Result<*mut c_void, PluginError> {
+        // SAFETY: the handle is open and `name` is a valid nul-terminated string
+        let symbol = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+        if symbol.is_null() {
+            return Err(PluginError::Symbol(name.to_string_lossy().into_owned()));
+        }
+        Ok(symbol)
+    }
+
+    /// The last error from the dynamic linker
+    fn error() -> String {
+        // SAFETY: `dlerror` returns either null or a valid nul-terminated string
+        let error = unsafe { libc::dlerror() };
+        if error.is_null() {
+            return "unknown error".into();
+        }
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(not(unix))]
+impl Library {
+    pub fn open(_: &Path) -> Result<Self, PluginError> {
+        Err(PluginError::Open(
+            "plugins are only supported on unix-like systems".into(),
+        ))
+    }
+
+    pub fn symbol(&self, name: &CStr) -> Result<*mut c_void, PluginError> {
+        Err(PluginError::Symbol(name.to_string_lossy().into_owned()))
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        // SAFETY: the handle is open, and nothing from the library outlives it
+        #[cfg(unix)]
+        unsafe {
+            libc::dlclose(self.0);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+/* A plugin providing one mono instrument, "ramp", playing 1, 2, .., len for the length given as
+ * its argument. Built by the plugin crate's tests, with ABI_VERSION defined on the command line. */
+
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef struct {
+    const char *name;
+    void *(*new)(const char *args);
+    void (*free)(void *instrument);
+    uint16_t (*channels)(const void *instrument);
+    int64_t (*length)(const void *instrument);
+    uint32_t (*sample_rate)(const void *instrument);
+    size_t (*fill)(const void *instrument, uint32_t start, float *frames, size_t len);
+} InstrumentDescriptor;
+
+typedef struct {
+    uint32_t abi_version;
+    const InstrumentDescriptor *instruments;
+    size_t instruments_len;
+} PluginDescriptor;
+
+static void *ramp_new(const char *args) {
+    if (!args) {
+        return NULL;
+    }
+    uint32_t *len = malloc(sizeof *len);
+    if (len) {
+        *len = (uint32_t)strtoul(args, NULL, 10);
+    }
+    return len;
+}
+
+static void ramp_free(void *instrument) { free(instrument); }
+
+static uint16_t ramp_channels(const void *instrument) {
+    (void)instrument;
+    return 1;
+}
+
+static int64_t ramp_length(const void *instrument) { return *(const uint32_t *)instrument; }
+
+static uint32_t ramp_sample_rate(const void *instrument) {
+    (void)instrument;
+    return 0;
+}
+
+static size_t ramp_fill(const void *instrument, uint32_t start, float *frames, size_t len) {
+    uint32_t total = *(const uint32_t *)instrument;
+    size_t written = 0;
+    for (uint32_t id = start; id < total && written < len; id++) {
+        frames[written++] = (float)(id + 1);
+    }
+    return written;
+}
+
+static const InstrumentDescriptor instruments[] = {{
+    .name = "ramp",
+    .new = ramp_new,
+    .free = ramp_free,
+    .channels = ramp_channels,
+    .length = ramp_length,
+    .sample_rate = ramp_sample_rate,
+    .fill = ramp_fill,
+}};
+
+static const PluginDescriptor descriptor = {
+    .abi_version = ABI_VERSION,
+    .instruments = instruments,
+    .instruments_len = 1,
+};
+
+const PluginDescriptor *plunder_plugin(void) { return &descriptor; }
@@ -0,0 +1,90 @@
+//! Loading a plugin built as an actual shared library, see `fixture.c`
+
+#![cfg(unix)]
+
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use plugin::{ABI_VERSION, Plugin, PluginError};
+use types::*;
+
+/// Build the fixture plugin against `abi_version`
+fn build(abi_version: u32) -> PathBuf {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("libfixture{abi_version}.so"));
+    let status = Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(&path)
+        .arg(format!("-DABI_VERSION={abi_version}"))
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixture.c"))
+        .status()
+        .expect("a C compiler is needed to build the fixture plugin");
+    assert!(status.success());
+    path
+}
+
+/// Whether the library at `path` is loaded into the process
+fn loaded(path: &Path) -> bool {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_NOLOAD) };
+    if handle.is_null() {
+        return false;
+    }
+    unsafe { libc::dlclose(handle) };
+    true
+}
+
+#[test]
+fn load() {
+    assert!(matches!(
+        Plugin::load("/nonexistent/libplugin.so"),
+        Err(PluginError::Open(_))
+    ));
+    assert!(matches!(
+        Plugin::load(build(ABI_VERSION + 1)),
+        Err(PluginError::AbiVersion(version)) if version == ABI_VERSION + 1
+    ));
+
+    let path = build(ABI_VERSION);
+    let plugin = Plugin::load(&path).unwrap();
+    assert!(loaded(&path));
+    assert_eq!(plugin.instruments().collect::<Vec<_>>(), ["ramp"]);
+
+    let ramp = plugin.construct("ramp", Some("3")).unwrap();
+    assert_eq!(ramp.name(), "ramp");
+    assert_eq!(ramp.length(), Some(3));
+    let mut frames = [Sample::F32([0.]); 4];
+    assert_eq!(Instrument::<1>::fill(&ramp, 0, &mut frames), 3);
+    assert_eq!(
+        frames[..3].iter().map(Sample::to_f32).collect::<Vec<_>>(),
+        vec![[1.], [2.], [3.]],
+    );
+
+    // Instruments keep the library open after the plugin is dropped
+    drop(plugin);
+    assert!(loaded(&path));
+    drop(ramp);
+    assert!(!loaded(&path));
+}
+
+#[test]
+fn missing_entry_point() {
+    // Any shared library that isn't a plugin
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("libnot_a_plugin.so");
+    let source = Path::new(env!("CARGO_TARGET_TMPDIR")).join("not_a_plugin.c");
+    std::fs::write(&source, "int not_a_plugin(void) { return 0; }\n").unwrap();
+    let status = Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(&path)
+        .arg(&source)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(matches!(
+        Plugin::load(&path),
+        Err(PluginError::Symbol(name)) if name == "plunder_plugin"
+    ));
+}
@@ -8,18 +8,274 @@ local plunder = {}
 -- ofWav
 --
 ---@class Instrument
----@type fun(path: string): Instrument
+---@field len integer? Length in frames, if known
+---@field channels integer
+---@field sampleRate integer? Sample rate in Hz, if the instrument has one
+---@field loopPoints [integer, integer]? Start (inclusive) and end (exclusive) of its loop
+---@field name string
+---@field tempo number? Tempo in beats per minute, if it's a loop that declares one
+---@field get fun(self: Instrument, id: integer): number? Each channel of frame `id`, or nothing past the end
+
+---@class SlicesConfig: {mode: ('transients' | 'markers')?, sensitivity: number?, minGap: number?}
+
+-- A wav file, or a region of one sharing its samples, as are edits, which apply as it's played.
+-- Positions are in frames, unless given in seconds as `{seconds = 1.5}`
+---@alias Position integer | {frames: integer} | {seconds: number}
+---@class OfWav: Instrument
+---@field loops [integer, integer][] Sustain loops from the file's `smpl` chunk, the first of which it loops over
+---@field cues {id: integer, position: integer, label: string?, length: integer?}[] Markers from the file's `cue ` chunk
+---@field beats integer? Number of beats the loop spans, from its `acid` chunk
+---@field rootNote integer? Midi note of the loop's root key, from its `acid` chunk
+---@field key string? Musical key, e.g. `"Am"`, from its `LIST INFO` or `bext` chunk
+---@field slice fun(self: OfWav, start: Position, end: Position?): OfWav Region from `start` to `end`, or to the end
+---@field chop fun(self: OfWav, n: integer): OfWav[] `n` slices of equal length, e.g. to index from a sheet's rows
+---@field slices fun(self: OfWav, conf: SlicesConfig?): OfWav[] Slices starting at each detected hit
+---@field reverse fun(self: OfWav): OfWav
+---@field gain fun(self: OfWav, db: number): OfWav
+---@field normalize fun(self: OfWav, db: number?): OfWav Scaled so that its peak is at `db`, or 0dB
+---@field fadeIn fun(self: OfWav, time: Position): OfWav
+---@field fadeOut fun(self: OfWav, time: Position): OfWav
+---@field trimSilence fun(self: OfWav, db: number?): OfWav Trimmed to the frames louder than `db`, or -60dB
+-- Loads a wav file, or a FLAC, Ogg Vorbis, MP3 or AIFF file if its name ends in `.flac`, `.ogg`,
+-- `.mp3` or `.aif(f)`
+---@type fun(path: string): OfWav
 plunder.ofWav = libplunder.ofWav
+---@type fun(path: string): OfWav
+plunder.ofFlac = libplunder.ofFlac
+-- Loads an Ogg Vorbis or MP3 file, trimming its encoder delay and padding
+---@type fun(path: string): OfWav
+plunder.ofLossy = libplunder.ofLossy
+-- Loads an uncompressed or byte-swapped (`sowt`) AIFF or AIFC file, looping over its sustain loop
+---@type fun(path: string): OfWav
+plunder.ofAiff = libplunder.ofAiff
+-- Decoded files are shared by every load of the same file until it changes, up to a capacity in
+-- bytes of samples (1GiB by default) past which the least recently loaded are evicted.
+--
+-- FLAC, Ogg Vorbis and MP3 files, and instruments played through effects, are also kept on disk
+-- across restarts, in `$PLUNDER_CACHE_DIR` or else `~/.cache/plunder`, which `directory` moves
+-- (or disables with `false`) and `purge` empties. Past `diskCapacity` in bytes (4GiB by default),
+-- the least recently used are removed.
+---@type {evict: fun(path: string): boolean, clear: fun(), capacity: fun(bytes: integer?): integer, usage: fun(): integer, integer, directory: fun(path: (string | false)?): string?, diskCapacity: fun(bytes: integer?): integer, purge: fun()}
+plunder.cache = libplunder.cache
+
+
+--
+-- Synths
+--
+---@alias Waveform 'sine' | 'saw' | 'square' | 'triangle' | 'noise'
+---@alias Envelope {attack: number?, decay: number?, sustain: number?, release: number?}
+---@class OscillatorConfig: {wave: Waveform?, envelope: Envelope?, gate: number?, gain: number?, sampleRate: integer?, note: number?, seed: integer?}
+
+-- An oscillator laid out like a midi instrument, each note (in semitones above C0) taking up
+-- `noteLen` frames, unless it only plays a single `note`
+---@class Oscillator: Instrument
+---@field noteLen integer
+---@field note fun(self: Oscillator, note: integer): integer? Id of the first frame of `note`
+---@type fun(conf: OscillatorConfig?): Oscillator
+plunder.oscillator = function(conf)
+  return libplunder.oscillator(conf or {})
+end
+
+for _, wave in ipairs { 'sine', 'saw', 'square', 'triangle', 'noise' } do
+  ---@param conf OscillatorConfig?
+  ---@return Oscillator
+  plunder[wave] = function(conf)
+    local c = {}
+    for k, v in pairs(conf or {}) do
+      c[k] = v
+    end
+    c.wave = wave
+    return libplunder.oscillator(c)
+  end
+end
+
+---@alias DrumKind 'kick' | 'snare' | 'hat' | 'clap'
+-- Any parameter left out takes the default of the drum's `kind`
+---@class DrumConfig: {kind: DrumKind?, pitch: number?, sweep: number?, sweepTime: number?, decay: number?, noiseDecay: number?, tone: number?, color: number?, bursts: integer?, burstSpacing: number?, gain: number?, sampleRate: integer?, seed: integer?}
+
+-- A single drum hit, synthesized from a pitch-swept body and filtered noise
+---@type fun(conf: DrumConfig?): Instrument
+plunder.drum = function(conf)
+  return libplunder.drum(conf or {})
+end
+
+for _, kind in ipairs { 'kick', 'snare', 'hat', 'clap' } do
+  ---@param conf DrumConfig?
+  ---@return Instrument
+  plunder[kind] = function(conf)
+    local c = {}
+    for k, v in pairs(conf or {}) do
+      c[k] = v
+    end
+    c.kind = kind
+    return libplunder.drum(c)
+  end
+end
+
+
+--
+-- Effects
+--
+-- Each effect plays an instrument of known length through once up front, returning the result (and
+-- up to 10 seconds of the effect ringing on) as an instrument
+---@alias EffectType 'gain' | 'lowpass' | 'highpass' | 'bandpass' | 'lowshelf' | 'highshelf' | 'peak' | 'delay' | 'reverb' | 'distortion' | 'compressor'
+---@class EffectConfig: {type: EffectType, db: number?, frequency: number?, q: number?, time: number?, feedback: number?, mix: number?, decay: number?, damping: number?, drive: number?, threshold: number?, ratio: number?, attack: number?, release: number?, makeup: number?}
+---@type fun(instrument: Instrument, conf: EffectConfig): Instrument
+plunder.effect = libplunder.effect
+
+---@param db number
+function plunder.gain(instrument, db)
+  return libplunder.effect(instrument, { type = 'gain', db = db })
+end
+
+for _, filter in ipairs { 'lowpass', 'highpass', 'bandpass' } do
+  ---@param instrument Instrument
+  ---@param frequency number
+  ---@param q number?
+  ---@return Instrument
+  plunder[filter] = function(instrument, frequency, q)
+    return libplunder.effect(instrument, { type = filter, frequency = frequency, q = q })
+  end
+end
+
+for _, filter in ipairs { 'lowshelf', 'highshelf', 'peak' } do
+  ---@param instrument Instrument
+  ---@param frequency number
+  ---@param db number
+  ---@param q number?
+  ---@return Instrument
+  plunder[filter] = function(instrument, frequency, db, q)
+    return libplunder.effect(instrument, { type = filter, frequency = frequency, db = db, q = q })
+  end
+end
+
+---@param time number Seconds between echoes
+---@param feedback number?
+---@param mix number?
+function plunder.delay(instrument, time, feedback, mix)
+  return libplunder.effect(instrument, { type = 'delay', time = time, feedback = feedback, mix = mix })
+end
+
+---@param decay number? Seconds to decay by 60dB
+---@param mix number?
+---@param damping number?
+function plunder.reverb(instrument, decay, mix, damping)
+  return libplunder.effect(instrument, { type = 'reverb', decay = decay, mix = mix, damping = damping })
+end
+
+---@param drive number? Gain in decibels into the clipper
+---@param mix number?
+function plunder.distortion(instrument, drive, mix)
+  return libplunder.effect(instrument, { type = 'distortion', drive = drive, mix = mix })
+end
+
+---@param conf {threshold: number?, ratio: number?, attack: number?, release: number?, makeup: number?}?
+function plunder.compressor(instrument, conf)
+  local c = { type = 'compressor' }
+  for k, v in pairs(conf or {}) do
+    c[k] = v
+  end
+  return libplunder.effect(instrument, c)
+end
+
+--
+-- Nesting
+--
+
+-- Metatables of objects which render `_instruments` into a `_buffer`, and so can be nested as
+-- instruments of one another
+---@type {[table]: true}
+local renderable = {}
+
+---@class Renderable: {_instruments: {[any]: Instrument | Renderable}?, _buffer: Instrument?, _parents: {[Renderable]: true}}
+
+---@param value any
+---@return boolean
+local function is_renderable(value)
+  return renderable[getmetatable(value)] ~= nil
+end
+
+-- Whether `target` can be reached through the objects nested in the instruments of `self`
+---@param self Renderable
+---@param target Renderable
+---@return boolean
+local function nests(self, target)
+  for _, instrument in pairs(self._instruments or {}) do
+    if instrument == target or (is_renderable(instrument) and nests(instrument, target)) then
+      return true
+    end
+  end
+  return false
+end
+
+-- Render `self` with nested objects replaced by their buffers, then re-render the objects nesting it
+--
+-- Rendering waits until every nested object has rendered, which re-renders `self` in turn
+---@param self Renderable
+local function render(self)
+  if not self._instruments then
+    return
+  end
+  local instruments = {}
+  local pending = false
+  for k, instrument in pairs(self._instruments) do
+    if is_renderable(instrument) then
+      instrument._parents[self] = true
+      pending = pending or instrument._buffer == nil
+      instrument = instrument._buffer
+    end
+    instruments[k] = instrument
+  end
+  if pending then
+    return
+  end
+  self._buffer = self:_render(instruments)
+  for parent in pairs(self._parents) do
+    render(parent)
+  end
+end
+
+---@param self Renderable
+---@param instruments {[any]: Instrument | Renderable}
+local function set_instruments(self, instruments)
+  local previous = self._instruments
+  self._instruments = instruments
+  if nests(self, self) then
+    self._instruments = previous
+    error('cannot nest an instrument inside itself', 3)
+  end
+  -- Objects no longer nested stop re-rendering `self`
+  local nested = {}
+  for _, instrument in pairs(instruments) do
+    nested[instrument] = true
+  end
+  for _, instrument in pairs(previous or {}) do
+    if is_renderable(instrument) and not nested[instrument] then
+      instrument._parents[self] = nil
+    end
+  end
+  render(self)
+end
+
+---@return {[Renderable]: true}
+local function new_parents()
+  return setmetatable({}, { __mode = 'k' })
+end
 
 
 --
 -- p1
 --
 
----@class P1Config: {interval: number}
----@alias P1InstrumentMap ({[string]: Instrument} | Instrument[])
+---@alias P1GrooveStep {timing: number?, velocity: number?}
+---@alias P1RowConfig {interval: number?, length: number?, stretch: number?, fit: boolean?}
+---@class P1Config: {interval: number, tempo: number?, swing: number?, groove: (string | P1GrooveStep[])?, rows: {[string]: P1RowConfig}?, lazy: boolean?}
+-- An instrument defined in Lua: `get` returns a number (mono), `{left, right}` (stereo), or nil past
+-- the end, for ids counting from 0
+---@alias LuaInstrument (fun(id: integer): (number | [number, number])?) | {get: fun(self, id: integer): (number | [number, number])?, len: (fun(self): integer)?, name: string?}
+---@alias P1InstrumentMap ({[string]: Instrument | Renderable | LuaInstrument} | (Instrument | Renderable | LuaInstrument)[])
 
----@class P1: {_conf: P1Config?; _sheet: string?; _instruments: P1InstrumentMap, _buffer: Instrument?}
+---@class P1: Renderable, {_conf: P1Config?; _sheet: string?; _instruments: P1InstrumentMap?}
 ---@field sheet fun(self: P1, sheet: string): P1
 ---@field instruments fun(self: P1, instruments: P1InstrumentMap): P1
 ---@field get {sheet: fun(P1): string; instruments: fun(P1): ({[string]: Instrument} | Instrument[])}
@@ -27,14 +283,23 @@ plunder.ofWav = libplunder.ofWav
 local p1 = {}
 p1.__metatable = {}
 p1.__metatable.__index = p1.__metatable
+
+---@param self P1
+---@param instruments P1InstrumentMap
+---@return Instrument?
+function p1.__metatable:_render(instruments)
+  if self._conf and self._sheet then
+    -- Passing the previous buffer lets unchanged rows skip rendering
+    return libplunder.p1.render(self._conf, self._sheet, instruments, self._buffer)
+  end
+end
+
 ---@param self P1
 ---@param sheet string
 ---@return P1
 function p1.__metatable:sheet(sheet)
   self._sheet = sheet
-  if self._conf and self._sheet and self._instruments then
-    self._buffer = libplunder.p1.render(self._conf, self._sheet, self._instruments)
-  end
+  render(self)
   return self
 end
 
@@ -42,22 +307,78 @@ end
 ---@param instruments P1InstrumentMap
 ---@return P1
 function p1.__metatable:instruments(instruments)
-  self._instruments = instruments
-  if self._conf and self._sheet and self._instruments then
-    self._buffer = libplunder.p1.render(self._conf, self._sheet, self._instruments)
-  end
+  set_instruments(self, instruments)
   return self
 end
 
 ---@param conf P1Config
 ---@return P1
 p1.new = function(conf)
-  local self = setmetatable({ _conf = conf }, p1.__metatable)
+  local self = setmetatable({ _conf = conf, _parents = new_parents() }, p1.__metatable)
   return self
 end
 
+renderable[p1.__metatable] = true
 plunder.p1 = p1
 
+
+--
+-- arrangement
+--
+
+---@class ArrangementConfig: {crossfade: number?}
+
+---@class Arrangement: Renderable, {_conf: ArrangementConfig; _order: string?; _instruments: P1InstrumentMap?}
+---@field order fun(self: Arrangement, order: string): Arrangement
+---@field sections fun(self: Arrangement, sections: P1InstrumentMap): Arrangement
+
+local arrangement = {}
+arrangement.__metatable = {}
+arrangement.__metatable.__index = arrangement.__metatable
+
+---@param self Arrangement
+---@param sections P1InstrumentMap
+---@return Instrument?
+function arrangement.__metatable:_render(sections)
+  if self._order then
+    return libplunder.arrangement.render(self._conf, self._order, sections)
+  end
+end
+
+---@param self Arrangement
+---@param order string
+---@return Arrangement
+function arrangement.__metatable:order(order)
+  self._order = order
+  render(self)
+  return self
+end
+
+---@param self Arrangement
+---@param sections P1InstrumentMap
+---@return Arrangement
+function arrangement.__metatable:sections(sections)
+  set_instruments(self, sections)
+  return self
+end
+
+---@param conf ArrangementConfig?
+---@return Arrangement
+arrangement.new = function(conf)
+  local self = setmetatable({ _conf = conf or {}, _parents = new_parents() }, arrangement.__metatable)
+  return self
+end
+
+renderable[arrangement.__metatable] = true
+plunder.arrangement = arrangement
+
+--
+-- Plugins
+--
+-- Load a shared library of instruments, returning a table of their constructors by name
+---@type fun(path: string): {[string]: fun(args: string?): Instrument}
+plunder.loadPlugin = libplunder.loadPlugin
+
 function plunder.midi(filename)
   return {
     notes = function(self, interval)
@@ -73,9 +394,28 @@ end
 
 function plunder.global()
   _G.p1 = plunder.p1
+  _G.arrangement = plunder.arrangement
   _G.ofWav = plunder.ofWav
+  _G.ofFlac = plunder.ofFlac
+  _G.ofLossy = plunder.ofLossy
+  _G.ofAiff = plunder.ofAiff
+  _G.oscillator = plunder.oscillator
+  for _, wave in ipairs { 'sine', 'saw', 'square', 'triangle', 'noise' } do
+    _G[wave] = plunder[wave]
+  end
+  _G.drum = plunder.drum
+  for _, kind in ipairs { 'kick', 'snare', 'hat', 'clap' } do
+    _G[kind] = plunder[kind]
+  end
   _G.midi = plunder.midi
+  for _, effect in ipairs {
+    'effect', 'gain', 'lowpass', 'highpass', 'bandpass', 'lowshelf', 'highshelf', 'peak', 'delay', 'reverb',
+    'distortion', 'compressor',
+  } do
+    _G[effect] = plunder[effect]
+  end
   _G.midi1 = plunder.midi1
+  _G.loadPlugin = plunder.loadPlugin
   return plunder
 end
 
@@ -1,32 +1,111 @@
-use std::sync::Arc;
-
 use mlua::prelude::*;
+use types::InstrumentFactory;
+
+/// Registers the constructor of each instrument into the `libplunder` table
+const INSTRUMENTS: &[fn(&Lua, &LuaTable) -> LuaResult<()>] = &[
+    register::<of_wav::OfWav>,
+    register::<of_wav::OfFlac>,
+    register::<of_wav::OfLossy>,
+    register::<of_wav::OfAiff>,
+    register::<p1::P1>,
+    register::<p1::Arrangement>,
+    register::<synth::Oscillator>,
+    register::<synth::Drum>,
+    register::<effects::Effect>,
+];
+
+// Instruments are usually `LuaUserData` too, whose own `register` would otherwise be ambiguous
+fn register<F: InstrumentFactory>(lua: &Lua, table: &LuaTable) -> LuaResult<()> {
+    F::register(lua, table)
+}
 
 #[mlua::lua_module(name = "libplunder")]
 pub fn init(lua: &Lua) -> LuaResult<LuaTable> {
     let table = lua.create_table()?;
-
-    // OfWav
+    for register in INSTRUMENTS {
+        register(lua, &table)?;
+    }
     table.set(
-        "ofWav",
-        LuaFunction::wrap(|path: String| {
-            of_wav::OfWav::load(path)
-                .map(|instrument| -> Box<dyn types::BiInstrument> { Box::new(instrument) })
-                .map_err(|err| LuaError::ExternalError(Arc::new(err)))
-        }),
+        "loadPlugin",
+        lua.create_function(|lua, path: String| plugin::Plugin::load(path)?.into_lua_table(lua))?,
     )?;
+    table.set("cache", cache(lua)?)?;
+    Ok(table)
+}
 
-    // P1
-    let p1_tbl = lua.create_table()?;
-    p1_tbl.set(
-        "render",
-        LuaFunction::wrap(|(config, sheet, instruments)| {
-            Ok(p1::P1::render(config, sheet, instruments)
-                .map_err(Into::<LuaError>::into)?
-                .map(|instrument| -> Box<dyn types::BiInstrument> { Box::new(instrument) }))
-        }),
-    )?;
-    table.set("p1", p1_tbl)?;
+/// Controls over the decoded files shared by every load of the same file, see [`of_wav::Cache`],
+/// and those kept on disk across restarts, see [`disk_cache::DiskCache`]
+fn cache(lua: &Lua) -> LuaResult<LuaTable> {
+    use disk_cache::DiskCache;
+    use of_wav::Cache;
 
+    let table = lua.create_table()?;
+    table.set(
+        "evict",
+        lua.create_function(|_, path: String| Ok(Cache::shared().evict(path)))?,
+    )?;
+    table.set(
+        "clear",
+        lua.create_function(|_, ()| {
+            Cache::shared().clear();
+            Ok(())
+        })?,
+    )?;
+    table.set(
+        "capacity",
+        lua.create_function(|_, capacity: Option<usize>| {
+            let mut cache = Cache::shared();
+            if let Some(capacity) = capacity {
+                cache.set_capacity(capacity);
+            }
+            Ok(cache.capacity())
+        })?,
+    )?;
+    table.set(
+        "usage",
+        lua.create_function(|_, ()| {
+            let cache = Cache::shared();
+            Ok((cache.size(), cache.files()))
+        })?,
+    )?;
+    // `false` disables the disk cache
+    table.set(
+        "directory",
+        lua.create_function(|_, directory: Option<LuaValue>| {
+            match directory {
+                Some(LuaValue::Boolean(false)) => DiskCache::set_shared(None),
+                Some(LuaValue::String(path)) => {
+                    DiskCache::set_shared(Some(path.to_str()?.to_string().into()))
+                }
+                Some(LuaValue::Nil) | None => {}
+                Some(other) => {
+                    return Err(LuaError::FromLuaConversionError {
+                        from: other.type_name(),
+                        to: "cache directory".into(),
+                        message: Some("expected a path, or false".into()),
+                    });
+                }
+            }
+            Ok(DiskCache::shared().map(|cache| cache.directory().display().to_string()))
+        })?,
+    )?;
+    table.set(
+        "diskCapacity",
+        lua.create_function(|_, capacity: Option<u64>| {
+            if let Some(capacity) = capacity {
+                DiskCache::set_shared_capacity(capacity);
+            }
+            Ok(DiskCache::shared_capacity())
+        })?,
+    )?;
+    table.set(
+        "purge",
+        lua.create_function(|_, ()| {
+            if let Some(cache) = DiskCache::shared() {
+                cache.clear()?;
+            }
+            Ok(())
+        })?,
+    )?;
     Ok(table)
 }
@@ -0,0 +1,357 @@
+//! Drums, synthesized from a pitch-swept body and filtered noise
+
+use std::f32::consts::TAU;
+
+use types::*;
+
+use crate::{SynthError, duration, noise};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DrumKind {
+    #[default]
+    Kick,
+    Snare,
+    Hat,
+    Clap,
+}
+
+/// Parameters of a drum, any of which left out take the defaults of its [`DrumKind`]
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DrumConfig {
+    pub kind: DrumKind,
+    /// Frequency in Hz that the body settles at
+    pub pitch: Option<f32>,
+    /// Multiple of `pitch` that the body starts at
+    pub sweep: Option<f32>,
+    /// Time constant in seconds of the body's fall from its starting pitch
+    pub sweep_time: Option<f32>,
+    /// Time constant in seconds of the body's decay
+    pub decay: Option<f32>,
+    /// Time constant in seconds of the noise's decay
+    pub noise_decay: Option<f32>,
+    /// Balance between the body (`1.0`) and the noise (`0.0`)
+    pub tone: Option<f32>,
+    /// Colour of the noise, from dark (`0.0`) to bright (`1.0`)
+    pub color: Option<f32>,
+    /// Number of bursts of noise, each restarting its decay (as in a clap)
+    pub bursts: Option<u32>,
+    /// Seconds between bursts of noise
+    pub burst_spacing: Option<f32>,
+    pub gain: Option<f32>,
+    pub sample_rate: Option<u32>,
+    /// Seed of the noise
+    pub seed: Option<u32>,
+}
+
+impl FromLua for DrumConfig {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        use serde::Deserialize as _;
+        DrumConfig::deserialize(LuaDeserializer::new(value))
+    }
+}
+
+/// Every parameter of a drum, see [`DrumConfig`]
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    pitch: f32,
+    sweep: f32,
+    sweep_time: f32,
+    decay: f32,
+    noise_decay: f32,
+    tone: f32,
+    color: f32,
+    bursts: u32,
+    burst_spacing: f32,
+    gain: f32,
+    sample_rate: u32,
+    seed: u32,
+}
+
+impl DrumKind {
+    fn voice(&self) -> Voice {
+        let voice = Voice {
+            pitch: 50.,
+            sweep: 4.,
+            sweep_time: 0.04,
+            decay: 0.3,
+            noise_decay: 0.01,
+            tone: 0.95,
+            color: 0.3,
+            bursts: 1,
+            burst_spacing: 0.,
+            gain: 0.8,
+            sample_rate: 44_100,
+            seed: 0,
+        };
+        match self {
+            DrumKind::Kick => voice,
+            DrumKind::Snare => Voice {
+                pitch: 180.,
+                sweep: 1.5,
+                sweep_time: 0.02,
+                decay: 0.08,
+                noise_decay: 0.12,
+                tone: 0.4,
+                color: 0.6,
+                ..voice
+            },
+            DrumKind::Hat => Voice {
+                decay: 0.,
+                noise_decay: 0.03,
+                tone: 0.,
+                color: 0.95,
+                gain: 0.5,
+                ..voice
+            },
+            DrumKind::Clap => Voice {
+                decay: 0.,
+                noise_decay: 0.1,
+                tone: 0.,
+                color: 0.6,
+                bursts: 3,
+                burst_spacing: 0.01,
+                ..voice
+            },
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            DrumKind::Kick => "kick",
+            DrumKind::Snare => "snare",
+            DrumKind::Hat => "hat",
+            DrumKind::Clap => "clap",
+        }
+    }
+}
+
+impl Voice {
+    fn new(config: &DrumConfig) -> Result<Self, SynthError> {
+        let voice = config.kind.voice();
+        let voice = Voice {
+            pitch: config.pitch.unwrap_or(voice.pitch),
+            sweep: config.sweep.unwrap_or(voice.sweep),
+            sweep_time: duration("sweep time", config.sweep_time.unwrap_or(voice.sweep_time))?,
+            decay: duration("decay", config.decay.unwrap_or(voice.decay))?,
+            noise_decay: duration(
+                "noise decay",
+                config.noise_decay.unwrap_or(voice.noise_decay),
+            )?,
+            tone: config.tone.unwrap_or(voice.tone),
+            color: config.color.unwrap_or(voice.color),
+            bursts: config.bursts.unwrap_or(voice.bursts).max(1),
+            burst_spacing: duration(
+                "burst spacing",
+                config.burst_spacing.unwrap_or(voice.burst_spacing),
+            )?,
+            gain: config.gain.unwrap_or(voice.gain),
+            sample_rate: config.sample_rate.unwrap_or(voice.sample_rate),
+            seed: config.seed.unwrap_or(voice.seed),
+        };
+        // Many bursts or long decays add up
+        duration("hit", voice.duration())?;
+        if voice.duration() as f64 * voice.sample_rate as f64 > u32::MAX as f64 {
+            return Err(SynthError::TooLong(voice.sample_rate));
+        }
+        Ok(voice)
+    }
+
+    /// Seconds until both the body and the noise have decayed below -60dB
+    fn duration(&self) -> f32 {
+        // ln(1000)
+        const DECAYS: f32 = 6.908;
+        let noise = (self.bursts - 1) as f32 * self.burst_spacing + self.noise_decay * DECAYS;
+        noise.max(self.decay * DECAYS)
+    }
+
+    fn render(&self) -> Vec<f32> {
+        let sample_rate = self.sample_rate as f32;
+        let len = (self.duration() * sample_rate).ceil() as usize;
+        let decay = |t: f32, time: f32| if time > 0. { (-t / time).exp() } else { 0. };
+        // One-pole lowpass splitting noise into its dark and bright halves
+        let lowpass = 1. - (-TAU * 2_000. / sample_rate).exp();
+
+        let (mut phase, mut low) = (0., 0.);
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / sample_rate;
+
+                let pitch = self.pitch * (1. + (self.sweep - 1.) * decay(t, self.sweep_time));
+                phase = (phase + pitch / sample_rate).fract();
+                let body = (phase * TAU).sin() * decay(t, self.decay);
+
+                let white = noise(self.seed, i as u32);
+                low += lowpass * (white - low);
+                let colored = (1. - self.color) * low + self.color * (white - low);
+                // Every burst restarts the noise's decay
+                let burst =
+                    ((t / self.burst_spacing.max(f32::EPSILON)) as u32).min(self.bursts - 1) as f32;
+                let since_burst = t - burst * self.burst_spacing;
+                let noise = colored * decay(since_burst, self.noise_decay);
+
+                self.gain * (self.tone * body + (1. - self.tone) * noise)
+            })
+            .collect()
+    }
+}
+
+/// A single mono drum hit, rendered up front
+pub struct Drum {
+    kind: DrumKind,
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl Drum {
+    pub fn new(config: &DrumConfig) -> Result<Self, SynthError> {
+        let voice = Voice::new(config)?;
+        Ok(Drum {
+            kind: config.kind,
+            sample_rate: voice.sample_rate,
+            samples: voice.render(),
+        })
+    }
+}
+
+impl Instrument<1> for Drum {
+    fn ok(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        self.samples.get(id as usize).map(|s| Sample::F32([*s]))
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<1>]) -> usize {
+        fill_from_slice(&self.samples, start, frames, |s| Sample::F32([s]))
+    }
+}
+
+impl Instrument<2> for Drum {
+    fn ok(&self) -> Result<(), String> {
+        // Mono audio is upmixed to stereo
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        self.samples.get(id as usize).map(|s| Sample::F32([*s; 2]))
+    }
+
+    fn fill(&self, start: u32, frames: &mut [Sample<2>]) -> usize {
+        fill_from_slice(&self.samples, start, frames, |s| Sample::F32([s; 2]))
+    }
+}
+
+impl BiInstrument for Drum {
+    fn length(&self) -> Option<u32> {
+        Some(self.samples.len() as u32)
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.sample_rate)
+    }
+
+    fn name(&self) -> String {
+        self.kind.name().into()
+    }
+}
+
+impl LuaUserData for Drum {}
+
+impl InstrumentFactory for Drum {
+    type Args = DrumConfig;
+    type Instrument = Drum;
+    const NAME: &str = "drum";
+
+    fn construct(config: DrumConfig) -> LuaResult<Option<Drum>> {
+        Ok(Some(Drum::new(&config)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0., |peak, s| s.abs().max(peak))
+    }
+
+    #[test]
+    fn drums_decay() {
+        for kind in [
+            DrumKind::Kick,
+            DrumKind::Snare,
+            DrumKind::Hat,
+            DrumKind::Clap,
+        ] {
+            let drum = Drum::new(&DrumConfig {
+                kind,
+                ..Default::default()
+            })
+            .unwrap();
+            let (head, tail) = drum.samples.split_at(drum.samples.len() / 10 * 9);
+            assert!(peak(head) > 0.1, "{kind:?} is silent");
+            assert!(peak(tail) < peak(head) / 100., "{kind:?} doesn't decay");
+        }
+    }
+
+    #[test]
+    fn parameters_override_kind() {
+        let drum = |decay| {
+            Drum::new(&DrumConfig {
+                kind: DrumKind::Kick,
+                decay,
+                ..Default::default()
+            })
+        };
+        assert!(drum(Some(1.)).unwrap().samples.len() > drum(None).unwrap().samples.len());
+    }
+
+    #[test]
+    fn durations_in_range() {
+        let drum = |config| Drum::new(&config).map(|drum| drum.samples.len());
+        assert!(matches!(
+            drum(DrumConfig {
+                decay: Some(1e9),
+                ..Default::default()
+            }),
+            Err(SynthError::Duration("decay", _))
+        ));
+        assert!(matches!(
+            drum(DrumConfig {
+                noise_decay: Some(f32::INFINITY),
+                ..Default::default()
+            }),
+            Err(SynthError::Duration("noise decay", _))
+        ));
+        assert!(matches!(
+            drum(DrumConfig {
+                sweep_time: Some(-1.),
+                ..Default::default()
+            }),
+            Err(SynthError::Duration("sweep time", _))
+        ));
+        // Each parameter is in range, but not the hit they add up to
+        assert!(matches!(
+            drum(DrumConfig {
+                kind: DrumKind::Clap,
+                bursts: Some(1_000_000),
+                ..Default::default()
+            }),
+            Err(SynthError::Duration("hit", _))
+        ));
+        assert!(matches!(
+            drum(DrumConfig {
+                sample_rate: Some(u32::MAX),
+                ..Default::default()
+            }),
+            Err(SynthError::TooLong(_))
+        ));
+    }
+}
@@ -0,0 +1,73 @@
+//! ADSR envelopes
+
+/// Attack, decay and release times in seconds, and the sustain level held in between
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    /// Level held after the decay until the note is released, from `0.0` to `1.0`
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope {
+            attack: 0.005,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.2,
+        }
+    }
+}
+
+impl Envelope {
+    /// Level of the envelope `t` seconds into a note released after `gate` seconds, or `None`
+    /// once the release has finished
+    pub fn level(&self, t: f32, gate: f32) -> Option<f32> {
+        if t < gate {
+            return Some(self.held(t));
+        }
+        let released = t - gate;
+        (released < self.release).then(|| self.held(gate) * (1. - released / self.release))
+    }
+
+    /// Length in seconds of a note released after `gate` seconds
+    pub fn duration(&self, gate: f32) -> f32 {
+        gate + self.release
+    }
+
+    /// Level before the note is released
+    fn held(&self, t: f32) -> f32 {
+        if t < self.attack {
+            t / self.attack
+        } else if t < self.attack + self.decay {
+            1. - (1. - self.sustain) * (t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level() {
+        let envelope = Envelope {
+            attack: 1.,
+            decay: 1.,
+            sustain: 0.5,
+            release: 2.,
+        };
+        assert_eq!(envelope.level(0.5, 3.), Some(0.5));
+        assert_eq!(envelope.level(1.5, 3.), Some(0.75));
+        assert_eq!(envelope.level(2.5, 3.), Some(0.5));
+        assert_eq!(envelope.level(4., 3.), Some(0.25));
+        assert_eq!(envelope.level(5., 3.), None);
+        // Notes released early fade out from wherever they got to
+        assert_eq!(envelope.level(1., 0.5), Some(0.375));
+    }
+}
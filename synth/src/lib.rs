@@ -0,0 +1,72 @@
+//! `synth`, procedural instruments for Plunder which need no sample files
+
+use std::{fmt, sync::Arc};
+
+use types::*;
+
+mod drum;
+mod envelope;
+mod oscillator;
+pub use drum::{Drum, DrumConfig, DrumKind};
+pub use envelope::Envelope;
+pub use oscillator::{Oscillator, OscillatorConfig, Waveform};
+
+/// Longest in seconds that any part of a note, or a whole drum hit, may last, which keeps every
+/// note an oscillator lays out within `u32` frames at common sample rates
+pub const MAX_DURATION: f32 = 60.;
+
+#[derive(Debug)]
+pub enum SynthError {
+    /// A duration in seconds that's negative, not a number, or above [`MAX_DURATION`]
+    Duration(&'static str, f32),
+    /// The instrument has more frames than ids to play them at its sample rate
+    TooLong(u32),
+}
+
+impl fmt::Display for SynthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SynthError::Duration(name, seconds) => write!(
+                f,
+                "A {name} of {seconds}s is out of range, expected 0 to {MAX_DURATION}s"
+            ),
+            SynthError::TooLong(sample_rate) => {
+                write!(f, "Instrument is too long to play at {sample_rate}Hz")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SynthError {}
+
+impl From<SynthError> for LuaError {
+    fn from(value: SynthError) -> Self {
+        LuaError::ExternalError(Arc::new(value))
+    }
+}
+
+/// Check that the duration `name` of `seconds` can be played, see [`MAX_DURATION`]
+fn duration(name: &'static str, seconds: f32) -> Result<f32, SynthError> {
+    match (0. ..=MAX_DURATION).contains(&seconds) {
+        true => Ok(seconds),
+        false => Err(SynthError::Duration(name, seconds)),
+    }
+}
+
+/// Frequency in Hz of C0, the lowest note of pitched instruments
+pub const C0: f32 = 16.351_598;
+
+/// Frequency in Hz of the note `note` semitones above C0
+pub fn frequency(note: f32) -> f32 {
+    C0 * (note / 12.).exp2()
+}
+
+/// White noise in `-1.0..1.0`, the same for every `seed` and `id` however it's played
+pub fn noise(seed: u32, id: u32) -> f32 {
+    // splitmix64
+    let mut x = ((seed as u64) << 32 | id as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x >> 40) as f32 / (1 << 23) as f32 - 1.
+}
@@ -0,0 +1,296 @@
+//! Oscillators, playing a waveform through an envelope
+
+use std::f32::consts::TAU;
+
+use types::*;
+
+use crate::{Envelope, SynthError, duration, frequency, noise};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+}
+
+impl Waveform {
+    /// Value of the wave `phase` cycles in, where noise instead depends on `seed` and `id`
+    pub fn at(&self, phase: f32, seed: u32, id: u32) -> f32 {
+        let phase = phase.fract();
+        match self {
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Saw => 2. * phase - 1.,
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.
+                } else {
+                    -1.
+                }
+            }
+            Waveform::Triangle => 1. - 4. * (phase - 0.5).abs(),
+            Waveform::Noise => noise(seed, id),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Waveform::Sine => "sine",
+            Waveform::Saw => "saw",
+            Waveform::Square => "square",
+            Waveform::Triangle => "triangle",
+            Waveform::Noise => "noise",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OscillatorConfig {
+    pub wave: Waveform,
+    pub envelope: Envelope,
+    /// Seconds that each note is held for before it's released
+    pub gate: f32,
+    pub gain: f32,
+    pub sample_rate: u32,
+    /// Only play this note, in semitones above C0, rather than laying out every note
+    pub note: Option<f32>,
+    /// Seed of the noise waveform
+    pub seed: u32,
+}
+
+impl Default for OscillatorConfig {
+    fn default() -> Self {
+        OscillatorConfig {
+            wave: Waveform::default(),
+            envelope: Envelope::default(),
+            gate: 0.25,
+            gain: 0.5,
+            sample_rate: 44_100,
+            note: None,
+            seed: 0,
+        }
+    }
+}
+
+impl FromLua for OscillatorConfig {
+    fn from_lua(value: LuaValue, _: &Lua) -> LuaResult<Self> {
+        use serde::Deserialize as _;
+        OscillatorConfig::deserialize(LuaDeserializer::new(value))
+    }
+}
+
+/// A mono oscillator laid out like a midi instrument
+///
+/// Each note occupies the same number of frames (its gate plus release), one after another from
+/// C0 upwards: id `0` is the first frame of C0, id `note_len` the first of C#0, and so on for
+/// [`Oscillator::NOTES`] notes. An oscillator playing a single `note` holds just that one.
+pub struct Oscillator {
+    config: OscillatorConfig,
+    note_len: u32,
+}
+
+impl Oscillator {
+    /// Number of notes laid out, C0 to B9
+    pub const NOTES: u32 = 120;
+
+    pub fn new(config: OscillatorConfig) -> Result<Self, SynthError> {
+        let envelope = &config.envelope;
+        duration("gate", config.gate)?;
+        duration("attack", envelope.attack)?;
+        duration("decay", envelope.decay)?;
+        duration("release", envelope.release)?;
+
+        let note_len = envelope.duration(config.gate) as f64 * config.sample_rate as f64;
+        let note_len = note_len.ceil().max(1.);
+        // Every frame of every note needs an id
+        let notes = match config.note {
+            Some(_) => 1,
+            None => Self::NOTES,
+        };
+        if note_len * notes as f64 > u32::MAX as f64 {
+            return Err(SynthError::TooLong(config.sample_rate));
+        }
+        Ok(Oscillator {
+            note_len: note_len as u32,
+            config,
+        })
+    }
+
+    /// Number of frames each note occupies
+    pub fn note_len(&self) -> u32 {
+        self.note_len
+    }
+
+    /// Id of the first frame of `note`, in semitones above C0
+    pub fn note(&self, note: u32) -> Option<u32> {
+        match self.config.note {
+            Some(_) => (note == 0).then_some(0),
+            None => (note < Self::NOTES).then(|| note * self.note_len),
+        }
+    }
+
+    fn notes(&self) -> u32 {
+        match self.config.note {
+            Some(_) => 1,
+            None => Self::NOTES,
+        }
+    }
+}
+
+impl Instrument<1> for Oscillator {
+    fn ok(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        let (region, offset) = (id / self.note_len, id % self.note_len);
+        if region >= self.notes() {
+            return None;
+        }
+        let config = &self.config;
+        let t = offset as f32 / config.sample_rate as f32;
+        let level = config.envelope.level(t, config.gate).unwrap_or(0.);
+        let phase = frequency(config.note.unwrap_or(region as f32)) * t;
+        let s = config.wave.at(phase, config.seed, id);
+        Some(Sample::F32([config.gain * level * s]))
+    }
+}
+
+impl Instrument<2> for Oscillator {
+    fn ok(&self) -> Result<(), String> {
+        // Mono audio is upmixed to stereo
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        Instrument::<1>::get(self, id).map(Sample::to_stereo)
+    }
+}
+
+impl BiInstrument for Oscillator {
+    fn length(&self) -> Option<u32> {
+        Some(self.notes() * self.note_len)
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.config.sample_rate)
+    }
+
+    fn name(&self) -> String {
+        self.config.wave.name().into()
+    }
+}
+
+impl LuaUserData for Oscillator {
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("noteLen", |_, this| Ok(this.note_len()));
+    }
+
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("note", |_, this, note: u32| Ok(this.note(note)));
+    }
+}
+
+impl InstrumentFactory for Oscillator {
+    type Args = OscillatorConfig;
+    type Instrument = Oscillator;
+    const NAME: &str = "oscillator";
+
+    fn construct(config: OscillatorConfig) -> LuaResult<Option<Oscillator>> {
+        Ok(Some(Oscillator::new(config)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_DURATION;
+
+    fn get(oscillator: &Oscillator, id: u32) -> Option<f32> {
+        Instrument::<1>::get(oscillator, id).map(|s| s.to_f32()[0])
+    }
+
+    #[test]
+    fn pitched_layout() {
+        let oscillator = Oscillator::new(OscillatorConfig {
+            envelope: Envelope {
+                attack: 0.,
+                decay: 0.,
+                sustain: 1.,
+                release: 0.,
+            },
+            gate: 1.,
+            gain: 1.,
+            sample_rate: 8_800,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(oscillator.note_len(), 8_800);
+
+        // A4 is 57 semitones above C0 at 440Hz, a period of 20 frames
+        let a4 = oscillator.note(57).unwrap();
+        assert!((get(&oscillator, a4 + 5).unwrap() - 1.).abs() < 1e-3);
+        assert!((get(&oscillator, a4 + 15).unwrap() + 1.).abs() < 1e-3);
+        assert_eq!(oscillator.note(Oscillator::NOTES), None);
+        assert_eq!(get(&oscillator, Oscillator::NOTES * 8_800), None);
+    }
+
+    #[test]
+    fn noise_is_deterministic() {
+        let oscillator = Oscillator::new(OscillatorConfig {
+            wave: Waveform::Noise,
+            note: Some(0.),
+            ..Default::default()
+        })
+        .unwrap();
+        let samples: Vec<_> = (0..1000).map(|id| get(&oscillator, id).unwrap()).collect();
+        assert_eq!(
+            samples,
+            (0..1000)
+                .map(|id| get(&oscillator, id).unwrap())
+                .collect::<Vec<_>>()
+        );
+        assert!(samples.iter().all(|s| s.abs() <= 0.5));
+        assert!(samples.windows(2).any(|s| s[0] != s[1]));
+    }
+
+    #[test]
+    fn durations_in_range() {
+        let oscillator = |gate, sample_rate| {
+            Oscillator::new(OscillatorConfig {
+                gate,
+                sample_rate,
+                ..Default::default()
+            })
+        };
+        assert!(matches!(
+            oscillator(-1., 44_100),
+            Err(SynthError::Duration("gate", _))
+        ));
+        assert!(matches!(
+            oscillator(f32::NAN, 44_100),
+            Err(SynthError::Duration("gate", _))
+        ));
+        assert!(matches!(
+            oscillator(800., 44_100),
+            Err(SynthError::Duration("gate", _))
+        ));
+        assert!(matches!(
+            oscillator(10., 10_000_000),
+            Err(SynthError::TooLong(_))
+        ));
+
+        let oscillator = oscillator(MAX_DURATION - 0.2, 192_000).unwrap();
+        let length = oscillator.length().unwrap();
+        assert_eq!(length, Oscillator::NOTES * oscillator.note_len());
+        assert!(Instrument::<1>::get(&oscillator, length - 1).is_some());
+    }
+}
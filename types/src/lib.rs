@@ -1,4 +1,8 @@
-pub mod registry_transfer;
+mod method_table;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+use method_table::MethodTable;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Sample<const CHANNELS: usize> {
@@ -20,133 +24,221 @@ impl<const CHANNELS: usize> Sample<CHANNELS> {
         }
     }
 
-    // pub fn to_f64(&self) -> f64 {
-    //     match self {
-    //         Sample::I16(val) => *val as f64 / i16::MAX as f64,
-    //         Sample::I24(bytes) => {
-    //             // Use u32 for bit manipulation, then cast to i32
-    //             let raw_value =
-    //                 u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) | (u32::from(bytes[2]) << 16);
-
-    //             // Sign-extend if the most significant bit is set
-    //             let sign_extended = if raw_value & 0x800000 != 0 {
-    //                 (raw_value | 0xFF000000) as i32 // Now safe: operating on u32 first
-    //             } else {
-    //                 raw_value as i32
-    //             };
-
-    //             sign_extended as f64 / 8_388_608.0 // 2^23
-    //         }
-    //         Sample::I32(val) => *val as f64 / i32::MAX as f64,
-    //         Sample::F32(val) => *val as f64,
-    //         Sample::F64(val) => *val,
-    //     }
-    // }
+    /// Convert to a frame of `f32`s normalized to `-1.0..=1.0`
+    pub fn to_f32(&self) -> [f32; CHANNELS] {
+        match self {
+            Sample::I16(s) => s.map(|s| s as f32 / 32_768.0), // 2^15
+            Sample::I24(s) => s.map(|s| i24_to_i32(s) as f32 / 8_388_608.0), // 2^23
+            Sample::I32(s) => s.map(|s| s as f32 / 2_147_483_648.0), // 2^31
+            Sample::F32(s) => *s,
+            Sample::F64(s) => s.map(|s| s as f32),
+        }
+    }
+}
+
+impl Sample<1> {
+    /// Upmix a mono sample by duplicating it onto both channels
+    pub fn to_stereo(self) -> Sample<2> {
+        match self {
+            Sample::I16([s]) => Sample::I16([s, s]),
+            Sample::I24([s]) => Sample::I24([s, s]),
+            Sample::I32([s]) => Sample::I32([s, s]),
+            Sample::F32([s]) => Sample::F32([s, s]),
+            Sample::F64([s]) => Sample::F64([s, s]),
+        }
+    }
+}
+
+impl Sample<2> {
+    /// Downmix a stereo sample by averaging both channels
+    pub fn to_mono(self) -> Sample<1> {
+        match self {
+            Sample::I16([l, r]) => Sample::I16([((l as i32 + r as i32) / 2) as i16]),
+            Sample::I24([l, r]) => Sample::I24([i32_to_i24((i24_to_i32(l) + i24_to_i32(r)) / 2)]),
+            Sample::I32([l, r]) => Sample::I32([((l as i64 + r as i64) / 2) as i32]),
+            Sample::F32([l, r]) => Sample::F32([(l + r) / 2.]),
+            Sample::F64([l, r]) => Sample::F64([(l + r) / 2.]),
+        }
+    }
+}
+
+/// Sign-extend a big-endian 24-bit sample
+fn i24_to_i32(bytes: [u8; 3]) -> i32 {
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]) >> 8
+}
+
+/// Truncate a 32-bit sample in the 24-bit range to its big-endian bytes
+fn i32_to_i24(value: i32) -> [u8; 3] {
+    value.to_be_bytes()[1..].try_into().unwrap()
 }
 
 pub trait Instrument<const CHANNELS: usize> {
     fn ok(&self) -> Result<(), String>;
     fn get(&self, id: u32) -> Option<Sample<CHANNELS>>;
+
+    /// Fill `frames` with consecutive samples starting from `start`, returning how many were
+    /// filled before the instrument ran out
+    ///
+    /// Instruments backed by a buffer should override this with a copy, see [`fill_from_slice`]
+    fn fill(&self, start: u32, frames: &mut [Sample<CHANNELS>]) -> usize {
+        for (i, frame) in frames.iter_mut().enumerate() {
+            match self.get(start + i as u32) {
+                Some(sample) => *frame = sample,
+                None => return i,
+            }
+        }
+        frames.len()
+    }
+}
+
+/// Fill `frames` from `samples[start..]`, converting each one with `f`, returning how many were
+/// filled
+pub fn fill_from_slice<S: Copy, const CHANNELS: usize>(
+    samples: &[S],
+    start: u32,
+    frames: &mut [Sample<CHANNELS>],
+    f: impl Fn(S) -> Sample<CHANNELS>,
+) -> usize {
+    let samples = samples.get(start as usize..).unwrap_or_default();
+    let n = samples.len().min(frames.len());
+    for (frame, sample) in frames.iter_mut().zip(&samples[..n]) {
+        *frame = f(*sample);
+    }
+    n
+}
+
+/// Amplitude of a level in decibels
+pub fn amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// Level in decibels of an amplitude
+pub fn decibels(amplitude: f32) -> f32 {
+    20. * amplitude.max(1e-9).log10()
 }
 
 pub use mlua::prelude::*;
 pub use mlua::serde::Deserializer as LuaDeserializer;
 pub use mlua::serde::Serializer as LuaSerializer;
 
-// pub trait InstrumentFactory {
-//     // TODO let factory create more complex APIs (e.g.: `samp.wav {'..'}`)
-//     type Args: FromLuaMulti;
-//     const NAME: &str;
-//     fn construct(args: Self::Args) -> LuaResult<Box<dyn BiInstrument>>;
-// }
-
-pub trait BiInstrument: Instrument<1> + Instrument<2> {}
-
-impl LuaUserData for Box<dyn BiInstrument> {}
-
-// pub struct InstrumentWrapper(pub Box<dyn BiInstrument>);
-// pub struct InstrumentWrapper<T>(pub DynInstrumentWrapper, PhantomData<T>);
-
-// impl<T> InstrumentWrapper<T>
-// where
-//     T: BiInstrument + Clone + 'static,
-// {
-//     pub fn new(instrument: T) -> Self {
-//         InstrumentWrapper(DynInstrumentWrapper(Box::new(instrument)), PhantomData)
-//     }
-// }
-// // impl<T> Deref for InstrumentWrapper<T> {
-// //     type Target = T;
-// //     fn deref(&self) -> &Self::Target {
-// //         &self.0
-// //     }
-// // }
-// // impl<T> DerefMut for InstrumentWrapper<T> {
-// //     fn deref_mut(&mut self) -> &mut Self::Target {
-// //         &mut self.0
-// //     }
-// // }
-
-// impl<T> Instrument<1> for InstrumentWrapper<T> {
-//     fn init(&self) -> Result<(), String> {
-//         <_ as Instrument<1>>::init(&*self.0.0)
-//     }
-//     fn get(&self, id: u32) -> Option<Sample<1>> {
-//         self.0.0.get(id)
-//     }
-// }
-
-// impl<T> Instrument<2> for InstrumentWrapper<T> {
-//     fn init(&self) -> Result<(), String> {
-//         <_ as Instrument<2>>::init(&*self.0.0)
-//     }
-//     fn get(&self, id: u32) -> Option<Sample<2>> {
-//         self.0.0.get(id)
-//     }
-// }
-
-// impl<T> BiInstrument for InstrumentWrapper<T> {}
-
-// // impl<T> AsRef<T> for InstrumentWrapper<T> {
-// //     fn as_ref(&self) -> &T {
-// //         &self.0
-// //     }
-// // }
-// // impl<T> AsMut<T> for InstrumentWrapper<T> {
-// //     fn as_mut(&mut self) -> &mut T {
-// //         &mut self.0
-// //     }
-// // }
-
-// impl<T> AsRef<Box<dyn BiInstrument>> for InstrumentWrapper<T> {
-//     fn as_ref(&self) -> &Box<dyn BiInstrument> {
-//         &self.0.0
-//     }
-// }
-
-// impl<T> AsMut<Box<dyn BiInstrument>> for InstrumentWrapper<T> {
-//     fn as_mut(&mut self) -> &mut Box<dyn BiInstrument> {
-//         &mut self.0.0
-//     }
-// }
-
-// impl<T: Instrument<1> + Instrument<2> + LuaUserData> LuaUserData for InstrumentWrapper<T> {
-//     fn register(registry: &mut LuaUserDataRegistry<Self>) {
-//         let mut registry = RegistryTransfer::new(registry);
-//         T::add_fields(&mut registry);
-//         T::add_methods(&mut registry);
-//     }
-// }
-
-// pub struct DynInstrumentWrapper(Box<dyn BiInstrument>);
-
-// impl LuaUserData for DynInstrumentWrapper {
-//     fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {}
-
-//     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {}
-
-//     fn register(registry: &mut LuaUserDataRegistry<Self>) {
-//         Self::add_fields(registry);
-//         Self::add_methods(registry);
-//     }
-// }
+/// A constructor of instruments, registered into the `libplunder` table as a Lua function
+pub trait InstrumentFactory {
+    // TODO let factory create more complex APIs (e.g.: `samp.wav {'..'}`)
+    type Args: FromLuaMulti;
+    /// The instrument constructed, whose own fields and methods are reachable from Lua once it's
+    /// boxed as a `Box<dyn BiInstrument>`
+    type Instrument: BiInstrument + LuaUserData;
+    /// Path of the function in the `libplunder` table, with `.` separating nested tables (e.g.
+    /// `p1.render`)
+    const NAME: &str;
+    fn construct(args: Self::Args) -> LuaResult<Option<Self::Instrument>>;
+
+    /// Set the constructor at [`Self::NAME`] in `table`, creating any tables along the way
+    fn register(lua: &Lua, table: &LuaTable) -> LuaResult<()> {
+        register_methods::<Self::Instrument>(lua)?;
+
+        let (path, name) = Self::NAME.rsplit_once('.').unwrap_or(("", Self::NAME));
+        let mut table = table.clone();
+        for key in path.split('.').filter(|key| !key.is_empty()) {
+            table = match table.get::<Option<LuaTable>>(key)? {
+                Some(inner) => inner,
+                None => {
+                    let inner = lua.create_table()?;
+                    table.set(key, &inner)?;
+                    inner
+                }
+            };
+        }
+        table.set(
+            name,
+            lua.create_function(|_, args| {
+                Ok(Self::construct(args)?
+                    .map(|instrument| -> Box<dyn BiInstrument> { Box::new(instrument) }))
+            })?,
+        )
+    }
+}
+
+pub trait BiInstrument: Instrument<1> + Instrument<2> + std::any::Any {
+    /// Start (inclusive) and end (exclusive) of the section this instrument loops over when
+    /// sustained past its end
+    fn loop_points(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    /// Length in frames, or `None` if it isn't known without playing the instrument through
+    fn length(&self) -> Option<u32> {
+        None
+    }
+
+    /// Number of channels the instrument produces before any up- or downmixing
+    fn channels(&self) -> u16 {
+        match Instrument::<2>::ok(self) {
+            Ok(()) => 2,
+            Err(_) => 1,
+        }
+    }
+
+    /// Sample rate in Hz that the instrument was recorded or rendered at, if it has one
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Name to display for the instrument, e.g. the file it was loaded from
+    fn name(&self) -> String {
+        "instrument".into()
+    }
+
+    /// Tempo in beats per minute that the instrument was recorded at, if it's a loop that has one
+    fn tempo(&self) -> Option<f32> {
+        None
+    }
+}
+
+/// Make the Lua fields and methods of `T` reachable on boxed instruments holding a `T`
+///
+/// Names are looked up in the fields and methods of the kind of instrument a box holds, so kinds
+/// of instruments may share names, and can be registered at any time (e.g. once a plugin is
+/// loaded). Those shared by every instrument take precedence.
+pub fn register_methods<T: BiInstrument + LuaUserData>(lua: &Lua) -> LuaResult<()> {
+    MethodTable::new::<T>(lua)?.register::<T>(lua);
+    Ok(())
+}
+
+impl LuaUserData for Box<dyn BiInstrument> {
+    /// Read-only metadata shared by every instrument
+    fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("len", |_, this| Ok(this.length()));
+        fields.add_field_method_get("channels", |_, this| Ok(this.channels()));
+        fields.add_field_method_get("sampleRate", |_, this| Ok(this.sample_rate()));
+        fields.add_field_method_get("loopPoints", |lua, this| {
+            this.loop_points()
+                .map(|(start, end)| lua.create_sequence_from([start, end]))
+                .transpose()
+        });
+        fields.add_field_method_get("name", |_, this| Ok(this.name()));
+        fields.add_field_method_get("tempo", |_, this| Ok(this.tempo()));
+    }
+
+    /// Methods shared by every instrument
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        // Each channel of a frame as a separate value normalized to `-1.0..=1.0`, or nothing past
+        // the end of the instrument
+        methods.add_method("get", |_, this, id: u32| {
+            let frame = match this.channels() {
+                1 => Instrument::<1>::get(&**this, id).map(|s| s.to_f32().to_vec()),
+                _ => Instrument::<2>::get(&**this, id).map(|s| s.to_f32().to_vec()),
+            };
+            Ok(LuaVariadic::from_iter(frame.unwrap_or_default()))
+        });
+        methods.add_meta_method(LuaMetaMethod::Len, |_, this, ()| Ok(this.length()));
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, ()| Ok(this.name()));
+        // Fields and methods of the kind of instrument boxed, see `register_methods`
+        methods.add_meta_function(LuaMetaMethod::Index, |lua, (this, key)| {
+            MethodTable::index(lua, this, key)
+        });
+        methods.add_meta_function(LuaMetaMethod::NewIndex, |lua, (this, key, value)| {
+            MethodTable::new_index(lua, this, key, value)
+        });
+    }
+}
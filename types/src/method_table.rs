@@ -0,0 +1,327 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    marker::PhantomData,
+};
+
+use mlua::prelude::*;
+
+use crate::BiInstrument;
+
+/// The fields and methods of one kind of instrument, looked up by name on boxed instruments
+/// holding one
+pub(crate) struct MethodTable {
+    /// Getters of fields, called with the boxed instrument
+    getters: LuaRegistryKey,
+    /// Setters of fields, called with the boxed instrument and the value set
+    setters: LuaRegistryKey,
+    /// Methods, and fields with a fixed value
+    index: LuaRegistryKey,
+}
+
+/// Method tables of every kind of instrument registered with a Lua state, kept as its app data
+pub(crate) struct MethodTables(HashMap<TypeId, MethodTable>);
+
+impl MethodTable {
+    /// Collect the fields and methods of `T` into a method table
+    pub(crate) fn new<T: BiInstrument + LuaUserData>(lua: &Lua) -> LuaResult<Self> {
+        let mut collector = Collector::<T> {
+            lua,
+            getters: lua.create_table()?,
+            setters: lua.create_table()?,
+            index: lua.create_table()?,
+            result: Ok(()),
+            t: PhantomData,
+        };
+        T::add_fields(&mut collector);
+        T::add_methods(&mut collector);
+        collector.result?;
+        Ok(MethodTable {
+            getters: lua.create_registry_value(collector.getters)?,
+            setters: lua.create_registry_value(collector.setters)?,
+            index: lua.create_registry_value(collector.index)?,
+        })
+    }
+
+    /// Store the method table of `T`, replacing any it already had
+    pub(crate) fn register<T: 'static>(self, lua: &Lua) {
+        match lua.app_data_mut::<MethodTables>() {
+            Some(mut tables) => _ = tables.0.insert(TypeId::of::<T>(), self),
+            None => {
+                let tables = HashMap::from([(TypeId::of::<T>(), self)]);
+                _ = lua.set_app_data(MethodTables(tables));
+            }
+        }
+    }
+
+    /// Look up `key` on `this`, a boxed instrument, in the method table of its kind
+    pub(crate) fn index(lua: &Lua, this: LuaAnyUserData, key: LuaValue) -> LuaResult<LuaValue> {
+        let Some([getters, _, index]) = MethodTable::tables_of(lua, &this)? else {
+            return Ok(LuaNil);
+        };
+        match getters.raw_get::<Option<LuaFunction>>(&key)? {
+            Some(getter) => getter.call(this),
+            None => index.raw_get(key),
+        }
+    }
+
+    /// Set `key` on `this`, a boxed instrument, through the method table of its kind
+    pub(crate) fn new_index(
+        lua: &Lua,
+        this: LuaAnyUserData,
+        key: LuaValue,
+        value: LuaValue,
+    ) -> LuaResult<()> {
+        let setter = match MethodTable::tables_of(lua, &this)? {
+            Some([_, setters, _]) => setters.raw_get::<Option<LuaFunction>>(&key)?,
+            None => None,
+        };
+        match setter {
+            Some(setter) => setter.call((this, value)),
+            None => Err(LuaError::RuntimeError(format!(
+                "field \"{}\" can't be set on this instrument",
+                key.to_string()?
+            ))),
+        }
+    }
+
+    /// Getters, setters and index of the kind of instrument in `this`, if it has a method table
+    fn tables_of(lua: &Lua, this: &LuaAnyUserData) -> LuaResult<Option<[LuaTable; 3]>> {
+        let type_id = {
+            let this = this.borrow::<Box<dyn BiInstrument>>()?;
+            let this: &dyn Any = &**this;
+            this.type_id()
+        };
+        // Tables are copied out, so that the app data isn't borrowed while they're used
+        let keys = lua.app_data_ref::<MethodTables>().and_then(|tables| {
+            let table = tables.0.get(&type_id)?;
+            Some(
+                [&table.getters, &table.setters, &table.index]
+                    .map(|key| lua.registry_value::<LuaTable>(key)),
+            )
+        });
+        let Some([getters, setters, index]) = keys else {
+            return Ok(None);
+        };
+        Ok(Some([getters?, setters?, index?]))
+    }
+}
+
+/// Collects the fields and methods `T` registers into the tables of a [`MethodTable`]
+///
+/// Each is wrapped in a function taking the boxed instrument, which it downcasts to a `T`.
+/// Metamethods are those shared by every instrument, so `T` can't register its own.
+struct Collector<'a, T> {
+    lua: &'a Lua,
+    getters: LuaTable,
+    setters: LuaTable,
+    index: LuaTable,
+    /// The first error met while collecting
+    result: LuaResult<()>,
+    t: PhantomData<T>,
+}
+
+fn unavailable(name: &str) -> LuaError {
+    LuaError::RuntimeError(format!("\"{name}\" is not available on this value"))
+}
+
+/// Borrow the `T` in a boxed instrument
+fn downcast<'a, T: 'static>(
+    this: &'a LuaUserDataRef<Box<dyn BiInstrument>>,
+    name: &str,
+) -> LuaResult<&'a T> {
+    let this: &dyn Any = &***this;
+    this.downcast_ref().ok_or_else(|| unavailable(name))
+}
+
+/// Mutably borrow the `T` in a boxed instrument
+fn downcast_mut<'a, T: 'static>(
+    this: &'a mut LuaUserDataRefMut<Box<dyn BiInstrument>>,
+    name: &str,
+) -> LuaResult<&'a mut T> {
+    let this: &mut dyn Any = &mut ***this;
+    this.downcast_mut().ok_or_else(|| unavailable(name))
+}
+
+impl<T> Collector<'_, T> {
+    fn set(&mut self, table: fn(&Self) -> &LuaTable, name: String, value: LuaResult<LuaValue>) {
+        if self.result.is_ok() {
+            self.result = value.and_then(|value| table(self).raw_set(name, value));
+        }
+    }
+
+    fn unsupported(&mut self, name: String) {
+        if self.result.is_ok() {
+            self.result = Err(LuaError::RuntimeError(format!(
+                "metamethod \"{name}\" can't be registered for a kind of instrument"
+            )));
+        }
+    }
+}
+
+impl<T: 'static> LuaUserDataFields<T> for Collector<'_, T> {
+    fn add_field<V>(&mut self, name: impl Into<String>, value: V)
+    where
+        V: IntoLua + 'static,
+    {
+        let value = value.into_lua(self.lua);
+        self.set(|c| &c.index, name.into(), value)
+    }
+
+    fn add_field_method_get<M, R>(&mut self, name: impl Into<String>, method: M)
+    where
+        M: Fn(&Lua, &T) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        R: IntoLua,
+    {
+        let name = name.into();
+        let key = name.clone();
+        let getter = self.lua.create_function(move |lua, this: LuaAnyUserData| {
+            let this = this.borrow::<Box<dyn BiInstrument>>()?;
+            method(lua, downcast(&this, &key)?)
+        });
+        self.set(|c| &c.getters, name, getter.map(LuaValue::Function))
+    }
+
+    fn add_field_method_set<M, A>(&mut self, name: impl Into<String>, mut method: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> LuaResult<()> + mlua::MaybeSend + 'static,
+        A: FromLua,
+    {
+        let name = name.into();
+        let key = name.clone();
+        let setter =
+            self.lua
+                .create_function_mut(move |lua, (this, value): (LuaAnyUserData, A)| {
+                    let mut this = this.borrow_mut::<Box<dyn BiInstrument>>()?;
+                    method(lua, downcast_mut(&mut this, &key)?, value)
+                });
+        self.set(|c| &c.setters, name, setter.map(LuaValue::Function))
+    }
+
+    fn add_field_function_get<F, R>(&mut self, name: impl Into<String>, function: F)
+    where
+        F: Fn(&Lua, LuaAnyUserData) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        R: IntoLua,
+    {
+        let getter = self.lua.create_function(function);
+        self.set(|c| &c.getters, name.into(), getter.map(LuaValue::Function))
+    }
+
+    fn add_field_function_set<F, A>(&mut self, name: impl Into<String>, mut function: F)
+    where
+        F: FnMut(&Lua, LuaAnyUserData, A) -> LuaResult<()> + mlua::MaybeSend + 'static,
+        A: FromLua,
+    {
+        let setter = self
+            .lua
+            .create_function_mut(move |lua, (this, value)| function(lua, this, value));
+        self.set(|c| &c.setters, name.into(), setter.map(LuaValue::Function))
+    }
+
+    fn add_meta_field<V>(&mut self, name: impl Into<String>, _: V)
+    where
+        V: IntoLua + 'static,
+    {
+        self.unsupported(name.into())
+    }
+
+    fn add_meta_field_with<F, R>(&mut self, name: impl Into<String>, _: F)
+    where
+        F: FnOnce(&Lua) -> LuaResult<R> + 'static,
+        R: IntoLua,
+    {
+        self.unsupported(name.into())
+    }
+}
+
+impl<T: 'static> LuaUserDataMethods<T> for Collector<'_, T> {
+    fn add_method<M, A, R>(&mut self, name: impl Into<String>, method: M)
+    where
+        M: Fn(&Lua, &T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let name = name.into();
+        let key = name.clone();
+        let method = self
+            .lua
+            .create_function(move |lua, (this, args): (LuaAnyUserData, A)| {
+                let this = this.borrow::<Box<dyn BiInstrument>>()?;
+                method(lua, downcast(&this, &key)?, args)
+            });
+        self.set(|c| &c.index, name, method.map(LuaValue::Function))
+    }
+
+    fn add_method_mut<M, A, R>(&mut self, name: impl Into<String>, mut method: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let name = name.into();
+        let key = name.clone();
+        let method = self
+            .lua
+            .create_function_mut(move |lua, (this, args): (LuaAnyUserData, A)| {
+                let mut this = this.borrow_mut::<Box<dyn BiInstrument>>()?;
+                method(lua, downcast_mut(&mut this, &key)?, args)
+            });
+        self.set(|c| &c.index, name, method.map(LuaValue::Function))
+    }
+
+    fn add_function<F, A, R>(&mut self, name: impl Into<String>, function: F)
+    where
+        F: Fn(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let function = self.lua.create_function(function);
+        self.set(|c| &c.index, name.into(), function.map(LuaValue::Function))
+    }
+
+    fn add_function_mut<F, A, R>(&mut self, name: impl Into<String>, function: F)
+    where
+        F: FnMut(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let function = self.lua.create_function_mut(function);
+        self.set(|c| &c.index, name.into(), function.map(LuaValue::Function))
+    }
+
+    fn add_meta_method<M, A, R>(&mut self, name: impl Into<String>, _: M)
+    where
+        M: Fn(&Lua, &T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.unsupported(name.into())
+    }
+
+    fn add_meta_method_mut<M, A, R>(&mut self, name: impl Into<String>, _: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.unsupported(name.into())
+    }
+
+    fn add_meta_function<F, A, R>(&mut self, name: impl Into<String>, _: F)
+    where
+        F: Fn(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.unsupported(name.into())
+    }
+
+    fn add_meta_function_mut<F, A, R>(&mut self, name: impl Into<String>, _: F)
+    where
+        F: FnMut(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.unsupported(name.into())
+    }
+}
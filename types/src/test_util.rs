@@ -0,0 +1,104 @@
+//! Instruments for testing code which plays other instruments
+
+use std::cell::Cell;
+
+use crate::{BiInstrument, Instrument, Sample};
+
+/// A mono instrument playing a list of frames, or one frame forever
+///
+/// Its other properties are left unknown unless they're set, e.g.
+/// `TestInstrument { tempo: Some(90.), ..TestInstrument::ramp(4) }`.
+pub struct TestInstrument {
+    pub frames: Vec<f32>,
+    /// Play the first of `frames` forever, rather than each of them once
+    pub endless: bool,
+    pub length: Option<u32>,
+    pub loop_points: Option<(u32, u32)>,
+    pub sample_rate: Option<u32>,
+    pub tempo: Option<f32>,
+    pub name: &'static str,
+    /// Frame past which playing fails, reporting so through `ok` from then on
+    pub fails_past: Option<u32>,
+    pub failed: Cell<bool>,
+}
+
+impl TestInstrument {
+    pub fn new(frames: Vec<f32>) -> Self {
+        TestInstrument {
+            frames,
+            endless: false,
+            length: None,
+            loop_points: None,
+            sample_rate: None,
+            tempo: None,
+            name: "test",
+            fails_past: None,
+            failed: Cell::new(false),
+        }
+    }
+
+    /// `n` frames counting up from 1
+    pub fn ramp(n: u32) -> Self {
+        TestInstrument::new((1..=n).map(|s| s as f32).collect())
+    }
+
+    /// Frames of 1 that never stop
+    pub fn drone() -> Self {
+        TestInstrument {
+            endless: true,
+            ..TestInstrument::new(vec![1.])
+        }
+    }
+}
+
+impl Instrument<1> for TestInstrument {
+    fn ok(&self) -> Result<(), String> {
+        match self.failed.get() {
+            true => Err("failed".into()),
+            false => Ok(()),
+        }
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<1>> {
+        if self.fails_past.is_some_and(|last| id > last) {
+            self.failed.set(true);
+        }
+        let frame = match self.endless {
+            true => self.frames.first(),
+            false => self.frames.get(id as usize),
+        };
+        frame.map(|s| Sample::F32([*s]))
+    }
+}
+
+impl Instrument<2> for TestInstrument {
+    fn ok(&self) -> Result<(), String> {
+        Err("mono only".into())
+    }
+
+    fn get(&self, id: u32) -> Option<Sample<2>> {
+        Instrument::<1>::get(self, id).map(Sample::to_stereo)
+    }
+}
+
+impl BiInstrument for TestInstrument {
+    fn loop_points(&self) -> Option<(u32, u32)> {
+        self.loop_points
+    }
+
+    fn length(&self) -> Option<u32> {
+        self.length
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    fn name(&self) -> String {
+        self.name.into()
+    }
+
+    fn tempo(&self) -> Option<f32> {
+        self.tempo
+    }
+}